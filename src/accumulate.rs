@@ -0,0 +1,306 @@
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::*;
+
+/// An associative operator a recursive call is combined with, such as the `+`
+/// in `n + sum(n - 1)`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// `a + b`, or the concatenation `a + &b`.
+    Add,
+    /// `a * b`
+    Mul,
+    /// `a.max(b)` or `max(a, b)`
+    Max,
+    /// `a.min(b)` or `min(a, b)`
+    Min,
+}
+
+impl Operator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Mul => "*",
+            Operator::Max => "max",
+            Operator::Min => "min",
+        }
+    }
+}
+
+/// The side of an operation the recursive call is on.
+#[derive(Clone, Copy)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// An operation combining two operands, either of which may hold the recursive
+/// call.
+pub struct Operation<'a> {
+    pub operator: Operator,
+    pub left: &'a mut Expr,
+    pub right: &'a mut Expr,
+    /// Whether the operation is a concatenation, as in `a + &b` or `a + "b"`.
+    pub concat: bool,
+    /// Whether the right operand is borrowed, as in `a + &b`.
+    pub borrowed: bool,
+}
+
+impl<'a> Operation<'a> {
+    pub fn of(expr: &'a mut Expr) -> Option<Self> {
+        let mut concat = true;
+        let mut borrowed = false;
+        let (operator, left, right): (_, &mut Expr, &mut Expr) = match expr {
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::Add(_),
+                right,
+                ..
+            }) => match &mut **right {
+                Expr::Reference(ExprReference {
+                    mutability: None,
+                    expr: right,
+                    ..
+                }) => {
+                    borrowed = true;
+                    (Operator::Add, left, right)
+                }
+                right @ Expr::Lit(ExprLit {
+                    lit: Lit::Str(_), ..
+                }) => (Operator::Add, left, right),
+                right => {
+                    concat = false;
+                    (Operator::Add, left, right)
+                }
+            },
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::Mul(_),
+                right,
+                ..
+            }) => {
+                concat = false;
+                (Operator::Mul, left, right)
+            }
+            Expr::MethodCall(ExprMethodCall {
+                receiver,
+                method,
+                turbofish: None,
+                args,
+                ..
+            }) if args.len() == 1 => {
+                concat = false;
+                let operator = min_or_max(method)?;
+                (operator, receiver, args.first_mut()?)
+            }
+            Expr::Call(ExprCall { func, args, .. }) if args.len() == 2 => {
+                let operator = match &**func {
+                    Expr::Path(ExprPath {
+                        qself: None, path, ..
+                    }) if is_cmp_path(path) => min_or_max(&path.segments.last()?.ident)?,
+                    _ => return None,
+                };
+                concat = false;
+                let mut args = args.iter_mut();
+                (operator, args.next()?, args.next()?)
+            }
+            _ => return None,
+        };
+
+        Some(Operation {
+            operator,
+            left: strip_parens(left),
+            right: strip_parens(right),
+            concat,
+            borrowed,
+        })
+    }
+}
+
+fn min_or_max(ident: &Ident) -> Option<Operator> {
+    if ident == "max" {
+        Some(Operator::Max)
+    } else if ident == "min" {
+        Some(Operator::Min)
+    } else {
+        None
+    }
+}
+
+/// Whether a path is `max`, `cmp::max`, `std::cmp::max` and the like.
+fn is_cmp_path(path: &Path) -> bool {
+    let segments: Vec<_> = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+
+    match segments.as_slice() {
+        [_] => true,
+        [cmp, _] => cmp == "cmp",
+        [krate, cmp, _] => (krate == "std" || krate == "core") && cmp == "cmp",
+        _ => false,
+    }
+}
+
+fn strip_parens(expr: &mut Expr) -> &mut Expr {
+    match expr {
+        Expr::Paren(expr_paren) => strip_parens(&mut expr_paren.expr),
+        expr => expr,
+    }
+}
+
+/// The operands pending around the recursive call, which the final result is
+/// combined with.
+///
+/// The operands on either side are folded into separate accumulators, so that
+/// operators need not be commutative. Accumulators start out as `None`, which
+/// stands for the identity element of the operator whatever the type. They hold
+/// values of the return type, which is what the operands must be, except for
+/// the right operands of a concatenation, which are appended to an empty value.
+pub struct Accumulator {
+    left: Ident,
+    right: Ident,
+    combine: Ident,
+    /// The return type, if it can be named.
+    ty: Option<Type>,
+    /// The operator, once the first operation has been met.
+    pub operator: Option<Operator>,
+    /// Whether any of the operations is a concatenation.
+    pub concat: bool,
+}
+
+impl Accumulator {
+    pub fn new(ty: Option<Type>) -> Self {
+        let span = Span::mixed_site();
+        Accumulator {
+            left: Ident::new("left", span),
+            right: Ident::new("right", span),
+            combine: Ident::new("combine", span),
+            ty,
+            operator: None,
+            concat: false,
+        }
+    }
+
+    /// Declares the accumulators and the function combining two values, which
+    /// only depends on the operator once the whole body has been visited.
+    pub fn declare(&self) -> TokenStream {
+        let Accumulator {
+            left,
+            right,
+            combine,
+            ..
+        } = self;
+        let operator = match self.operator {
+            Some(operator) => operator,
+            None => return TokenStream::new(),
+        };
+        let (a, b) = (pending(), operand());
+        let combined = match operator {
+            Operator::Add if self.concat => quote!(#a + &#b),
+            Operator::Add => quote!(#a + #b),
+            Operator::Mul => quote!(#a * #b),
+            Operator::Max => quote!(#a.max(#b)),
+            Operator::Min => quote!(#a.min(#b)),
+        };
+        let (ty, option_ty) = match &self.ty {
+            Some(ty) => (Some(quote!(: #ty)), Some(quote!(: Option<#ty>))),
+            None => (None, None),
+        };
+
+        quote! {
+            #[allow(unused_mut)]
+            let mut #left #option_ty = None;
+            #[allow(unused_mut)]
+            let mut #right #option_ty = None;
+            let #combine = |#a #ty, #b #ty| #combined;
+        }
+    }
+
+    /// Folds an operand found on the other side of the recursive call into the
+    /// accumulator of that side.
+    pub fn accumulate(&self, side: Side, operation: &Operation) -> TokenStream {
+        let Accumulator { combine, .. } = self;
+        let Operation {
+            left,
+            right,
+            concat,
+            borrowed,
+            ..
+        } = operation;
+        let (pending, value) = (pending(), operand());
+        let ty = self.ty.as_ref().map(|ty| quote!(: #ty));
+
+        let (accumulator, operand, combined) = match side {
+            // `operand OP f(..)`
+            Side::Right => (
+                &self.left,
+                quote!(#left),
+                quote!(#combine(#pending, #value)),
+            ),
+            // `f(..) + &operand`, whose operand is appended to an empty value
+            // to own it.
+            Side::Left if *concat => {
+                let borrow = if *borrowed { Some(quote!(&)) } else { None };
+                let empty = match &self.ty {
+                    Some(ty) => quote!(<#ty as Default>::default()),
+                    None => quote!(Default::default()),
+                };
+                (
+                    &self.right,
+                    quote!(#empty + #borrow #right),
+                    quote!(#combine(#value, #pending)),
+                )
+            }
+            // `f(..) OP operand`
+            Side::Left => (
+                &self.right,
+                quote!(#right),
+                quote!(#combine(#value, #pending)),
+            ),
+        };
+
+        quote! {
+            let #value #ty = #operand;
+            #accumulator = Some(match #accumulator {
+                Some(#pending) => #combined,
+                None => #value,
+            });
+        }
+    }
+
+    /// Combines the result of the last iteration with the pending operands.
+    pub fn finish(&self, value: &Ident) -> TokenStream {
+        let Accumulator {
+            left,
+            right,
+            combine,
+            ..
+        } = self;
+        let pending = pending();
+
+        if self.operator.is_none() {
+            return quote!(#value);
+        }
+
+        quote! {{
+            let #value = match #left {
+                Some(#pending) => #combine(#pending, #value),
+                None => #value,
+            };
+            match #right {
+                Some(#pending) => #combine(#value, #pending),
+                None => #value,
+            }
+        }}
+    }
+}
+
+fn pending() -> Ident {
+    Ident::new("pending", Span::mixed_site())
+}
+
+fn operand() -> Ident {
+    Ident::new("operand", Span::mixed_site())
+}
@@ -0,0 +1,305 @@
+use quote::ToTokens;
+use syn::{spanned::Spanned, *};
+
+use crate::validate::combine;
+
+/// Arguments of the `#[recursive(..)]` attribute.
+#[derive(Clone, Default)]
+pub struct Args {
+    /// Whether operands combined with the recursive call through an associative
+    /// operator, as in `n + f(n - 1)`, are accumulated, given as `accumulate`.
+    /// The operator is one of `+`, `*`, `max` and `min`, the same throughout the
+    /// function, and the right operand of `&&` and `||` is a tail position.
+    pub accumulate: bool,
+    /// Accumulator parameters hidden from the signature, which start out with
+    /// the given value, given as `acc(name = value, ..)`, or with the default
+    /// value of their type, given as `acc(name, ..)`.
+    pub acc: Vec<HiddenParam>,
+    /// Whether calls that are not tail calls are made through continuations
+    /// on the heap, given as `cps`. Unlike with `stack`, they may be made in
+    /// the branches of any expression, not only of those leading to the result,
+    /// and in `for` loops, including the closures given to `map`, `for_each`
+    /// and `fold`, which are rewritten into loops, but not in other closures,
+    /// whose callers wait for their value.
+    pub cps: bool,
+    /// Whether the expansion is printed at compile time, given as `debug`.
+    pub debug: bool,
+    /// User macros that expand some of their arguments in tail position, given
+    /// as `macros(name(1, 2), ..)` with the indices of those arguments.
+    /// Recursive calls in those arguments are eliminated.
+    pub macros: Vec<TailMacro>,
+    /// The depth of recursion beyond which the function panics, given as
+    /// `max_depth = N`. With `stack` or `cps`, it is the number of calls in
+    /// progress; otherwise, as every call replaces its caller, it is the
+    /// number of calls made.
+    pub max_depth: Option<u64>,
+    /// The module the function is declared in, given as `module(crate::m)`.
+    /// Calls through its path, as in `crate::m::f(..)`, are then recognised as
+    /// recursive calls, which other module paths never are.
+    pub module: Option<Path>,
+    /// Whether calls that are not tail calls are made through a stack of frames
+    /// on the heap, given as `stack`. Recursive calls are hoisted out of the
+    /// expressions leading to the result and made before the rest of them is
+    /// evaluated.
+    pub stack: bool,
+    /// Whether recursive calls left as they are, given as `strict`, are errors
+    /// rather than warnings.
+    pub strict: bool,
+}
+
+/// A parameter hidden from the signature, with its initial value.
+#[derive(Clone)]
+pub struct HiddenParam {
+    pub name: Ident,
+    /// The initial value, or `None` for the default value of the type.
+    pub value: Option<Lit>,
+}
+
+/// A user macro expanding some of its arguments in tail position.
+#[derive(Clone)]
+pub struct TailMacro {
+    pub name: Ident,
+    /// The indices of the arguments in tail position, which must be given, as
+    /// the others may be evaluated before them, such as a condition.
+    pub tails: Vec<usize>,
+}
+
+/// How an option is given.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// `name`
+    Flag,
+    /// `name = value`
+    Value,
+    /// `name(a, b, ..)`
+    List,
+}
+
+const OPTIONS: &[(&str, Kind)] = &[
+    ("acc", Kind::List),
+    ("accumulate", Kind::Flag),
+    ("cps", Kind::Flag),
+    ("debug", Kind::Flag),
+    ("macros", Kind::List),
+    ("max_depth", Kind::Value),
+    ("module", Kind::List),
+    ("stack", Kind::Flag),
+    ("strict", Kind::Flag),
+];
+
+/// Pairs of options that transform the function in different ways.
+const EXCLUSIVE: &[(&str, &str)] = &[
+    ("accumulate", "cps"),
+    ("accumulate", "stack"),
+    ("cps", "stack"),
+];
+
+impl Args {
+    pub fn parse(args: AttributeArgs) -> Result<Self> {
+        let mut parsed = Args::default();
+        let mut seen = vec![];
+
+        let errors = args
+            .into_iter()
+            .filter_map(|arg| parsed.parse_option(arg, &mut seen).err());
+        combine(errors.collect::<Vec<_>>())?;
+
+        Ok(parsed)
+    }
+
+    fn parse_option(&mut self, arg: NestedMeta, seen: &mut Vec<String>) -> Result<()> {
+        let meta = match arg {
+            NestedMeta::Meta(meta) => meta,
+            NestedMeta::Lit(lit) => {
+                return Err(Error::new(
+                    lit.span(),
+                    "expected an option, found a literal",
+                ));
+            }
+        };
+        let name = match meta.path().get_ident() {
+            Some(ident) => ident.to_string(),
+            None => return Err(Error::new(meta.path().span(), "expected an option name")),
+        };
+
+        let kind = match OPTIONS.iter().find(|(option, _)| *option == name) {
+            Some((_, kind)) => *kind,
+            None => return Err(unknown_option(&meta, &name)),
+        };
+        let given = match meta {
+            Meta::Path(_) => Kind::Flag,
+            Meta::NameValue(_) => Kind::Value,
+            Meta::List(_) => Kind::List,
+        };
+        if given != kind {
+            let expected = match kind {
+                Kind::Flag => format!("`{}` takes no value", name),
+                Kind::Value => format!("expected `{} = ..`", name),
+                Kind::List => format!("expected `{}(..)`", name),
+            };
+            return Err(Error::new(meta.span(), expected));
+        }
+
+        if seen.contains(&name) {
+            let message = format!("`{}` is given more than once", name);
+            return Err(Error::new(meta.span(), message));
+        }
+        let conflict = EXCLUSIVE.iter().find_map(|options| match *options {
+            (a, b) if a == name && seen.iter().any(|option| option == b) => Some(b),
+            (a, b) if b == name && seen.iter().any(|option| option == a) => Some(a),
+            _ => None,
+        });
+        if let Some(other) = conflict {
+            let message = format!("`{}` cannot be combined with `{}`", name, other);
+            return Err(Error::new(meta.span(), message));
+        }
+        seen.push(name.clone());
+
+        match meta {
+            Meta::Path(path) if path.is_ident("accumulate") => self.accumulate = true,
+            Meta::Path(path) if path.is_ident("cps") => self.cps = true,
+            Meta::Path(path) if path.is_ident("debug") => self.debug = true,
+            Meta::Path(path) if path.is_ident("stack") => self.stack = true,
+            Meta::Path(path) if path.is_ident("strict") => self.strict = true,
+            Meta::List(list) if list.path.is_ident("acc") => {
+                for nested in list.nested {
+                    let (path, value) = match nested {
+                        NestedMeta::Meta(Meta::Path(path)) => (path, None),
+                        NestedMeta::Meta(Meta::NameValue(name_value)) => {
+                            (name_value.path, Some(name_value.lit))
+                        }
+                        nested => {
+                            let message = "expected `name = value` or `name`";
+                            return Err(Error::new(nested.span(), message));
+                        }
+                    };
+                    match path.get_ident() {
+                        Some(name) => self.acc.push(HiddenParam {
+                            name: name.clone(),
+                            value,
+                        }),
+                        None => return Err(Error::new(path.span(), "expected a parameter name")),
+                    }
+                }
+            }
+            Meta::List(list) if list.path.is_ident("macros") => {
+                for nested in list.nested {
+                    let (path, tails) = match nested {
+                        NestedMeta::Meta(Meta::Path(path)) => {
+                            let message = format!(
+                                "give the indices of the arguments `{0}!` expands in tail \
+                                 position, as in `macros({0}(1, 2))`",
+                                path.to_token_stream()
+                            );
+                            return Err(Error::new(path.span(), message));
+                        }
+                        NestedMeta::Meta(Meta::List(list)) => {
+                            let mut tails = vec![];
+                            for nested in &list.nested {
+                                match nested {
+                                    NestedMeta::Lit(Lit::Int(lit_int)) => {
+                                        tails.push(lit_int.base10_parse::<usize>()?)
+                                    }
+                                    nested => {
+                                        let message = "expected the index of an argument";
+                                        return Err(Error::new(nested.span(), message));
+                                    }
+                                }
+                            }
+                            (list.path, tails)
+                        }
+                        nested => return Err(Error::new(nested.span(), "expected a macro name")),
+                    };
+                    match path.get_ident() {
+                        Some(name) => self.macros.push(TailMacro {
+                            name: name.clone(),
+                            tails,
+                        }),
+                        None => return Err(Error::new(path.span(), "expected a macro name")),
+                    }
+                }
+            }
+            Meta::List(list) if list.path.is_ident("module") => {
+                let mut nested = list.nested.iter();
+                match (nested.next(), nested.next()) {
+                    (Some(NestedMeta::Meta(Meta::Path(path))), None)
+                        if path.leading_colon.is_none()
+                            && path.segments[0].ident == "crate"
+                            && path
+                                .segments
+                                .iter()
+                                .all(|segment| segment.arguments.is_empty()) =>
+                    {
+                        self.module = Some(path.clone())
+                    }
+                    _ => {
+                        let message = "expected the path of the function's module, as in \
+                                       `module(crate::m)`";
+                        return Err(Error::new(list.nested.span(), message));
+                    }
+                }
+            }
+            Meta::NameValue(name_value) if name_value.path.is_ident("max_depth") => {
+                let max_depth = match &name_value.lit {
+                    Lit::Int(lit_int) => lit_int.base10_parse::<u64>().ok(),
+                    _ => None,
+                };
+                match max_depth {
+                    Some(max_depth) => self.max_depth = Some(max_depth),
+                    None => {
+                        let message = "expected a number of iterations";
+                        return Err(Error::new(name_value.lit.span(), message));
+                    }
+                }
+            }
+            _ => unreachable!("option `{}` is not handled", name),
+        }
+
+        Ok(())
+    }
+}
+
+/// The error for an unknown option, suggesting the closest known one.
+fn unknown_option(meta: &Meta, name: &str) -> Error {
+    let suggestion = OPTIONS
+        .iter()
+        .map(|(option, _)| (edit_distance(name, option), option))
+        .filter(|(distance, option)| *distance <= option.len() / 3 + 1)
+        .min();
+
+    let message = match suggestion {
+        Some((_, option)) => format!("unknown option `{}`, did you mean `{}`?", name, option),
+        None => {
+            let options: Vec<_> = OPTIONS
+                .iter()
+                .map(|(option, _)| format!("`{}`", option))
+                .collect();
+            format!(
+                "unknown option `{}`, expected one of {}",
+                name,
+                options.join(", ")
+            )
+        }
+    };
+
+    Error::new(meta.path().span(), message)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + (a != *b) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
@@ -0,0 +1,140 @@
+use quote::quote;
+use syn::{visit::Visit, *};
+
+use crate::args::Args;
+use crate::module::{expand_components, is_annotated, parse_args, print_report, CallGraph};
+use crate::RecursionTransformer;
+
+/// Expands `#[recursive]` on an `impl` block.
+///
+/// The methods and associated functions calling themselves are transformed as
+/// if they were annotated, with the options given to the block, and those
+/// calling each other through `Self` together, as in an inline module. Those
+/// annotated on their own, or `default` in a specializing impl, are left as
+/// they are.
+pub fn expand_impl(args: AttributeArgs, item_impl: ItemImpl) -> Result<ItemImpl> {
+    let parsed = parse_args(args.clone(), "an `impl` block")?;
+    let mut report = vec![];
+
+    let mut items: Vec<Option<ImplItem>> = vec![];
+    let mut functions: Vec<(usize, ItemFn)> = vec![];
+    for item in item_impl.items {
+        match item {
+            ImplItem::Method(method)
+                if method.defaultness.is_none() && !is_annotated(&method.attrs) =>
+            {
+                let item_fn = ItemFn {
+                    attrs: method.attrs,
+                    vis: method.vis,
+                    sig: method.sig,
+                    block: Box::new(method.block),
+                };
+                functions.push((items.len(), item_fn));
+                items.push(None);
+            }
+            item => items.push(Some(item)),
+        }
+    }
+
+    let (indices, item_fns): (Vec<_>, Vec<_>) = functions.into_iter().unzip();
+    let mut graph = CallGraph::new(&item_fns.iter().collect::<Vec<_>>());
+    // Method calls through other receivers, such as `other.f()`, reach the
+    // function as well.
+    for (index, item_fn) in item_fns.iter().enumerate() {
+        if !graph.calls[index].contains(&index) && calls_itself(item_fn) {
+            graph.calls[index].push(index);
+        }
+    }
+    let expanded = expand_components(&args, &item_fns, &graph, &mut report);
+    for (slot, tokens) in indices.into_iter().zip(expanded) {
+        items[slot] = Some(ImplItem::Verbatim(tokens));
+    }
+
+    if parsed.debug {
+        let self_ty = &item_impl.self_ty;
+        print_report(&format!("impl {}", quote!(#self_ty)), &report);
+    }
+
+    Ok(ItemImpl {
+        items: items.into_iter().flatten().collect(),
+        ..item_impl
+    })
+}
+
+/// Expands `#[recursive]` on a trait.
+///
+/// The default methods calling themselves through `Self` are transformed as if
+/// they were annotated, with the options given to the trait. A default method
+/// only runs for the types not overriding it, so its calls to itself are calls
+/// to the same body.
+pub fn expand_trait(args: AttributeArgs, item_trait: ItemTrait) -> Result<ItemTrait> {
+    let parsed = parse_args(args.clone(), "a trait")?;
+    let mut report = vec![];
+
+    let items = item_trait
+        .items
+        .into_iter()
+        .map(|item| match item {
+            TraitItem::Method(method)
+                if method.default.is_some() && !is_annotated(&method.attrs) =>
+            {
+                let default = method.default.clone().expect("checked above");
+                let item_fn = ItemFn {
+                    attrs: method.attrs.clone(),
+                    vis: Visibility::Inherited,
+                    sig: method.sig.clone(),
+                    block: Box::new(default),
+                };
+                if !calls_itself(&item_fn) {
+                    return TraitItem::Method(method);
+                }
+
+                report.push(format!("`{}` calls itself", method.sig.ident));
+                let expanded =
+                    crate::expand(args.clone(), item_fn).map(|ItemFn { sig, block, .. }| {
+                        TraitItemMethod {
+                            sig,
+                            default: Some(*block),
+                            ..method.clone()
+                        }
+                    });
+                TraitItem::Verbatim(crate::expanded_or_kept(expanded, method))
+            }
+            item => item,
+        })
+        .collect();
+
+    if parsed.debug {
+        print_report(&format!("trait {}", item_trait.ident), &report);
+    }
+
+    Ok(ItemTrait {
+        items,
+        ..item_trait
+    })
+}
+
+/// Whether a function calls itself anywhere in its body, so that it is worth
+/// transforming.
+fn calls_itself(item_fn: &ItemFn) -> bool {
+    struct CallFinder {
+        transformer: RecursionTransformer,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for CallFinder {
+        fn visit_expr(&mut self, node: &'ast Expr) {
+            self.found |= self.transformer.is_recursive_call(node);
+            visit::visit_expr(self, node);
+        }
+
+        fn visit_item(&mut self, _node: &'ast Item) {}
+    }
+
+    let mut finder = CallFinder {
+        transformer: RecursionTransformer::new(item_fn.clone(), Args::default()),
+        found: false,
+    };
+    finder.visit_block(&item_fn.block);
+    finder.found
+}
@@ -0,0 +1,644 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::{punctuated::Punctuated, *};
+
+use crate::scope;
+use crate::target::mentions;
+use crate::RecursionTransformer;
+
+/// A collection the result of a recursive call can be built upon, going by
+/// the last segment of the return type.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Collection {
+    Vec,
+    VecDeque,
+    String,
+}
+
+impl Collection {
+    pub fn of(ty: &Type) -> Option<Self> {
+        let segment = match ty {
+            Type::Path(TypePath { qself: None, path }) => path.segments.last()?,
+            _ => return None,
+        };
+
+        if segment.ident == "Vec" {
+            Some(Collection::Vec)
+        } else if segment.ident == "VecDeque" {
+            Some(Collection::VecDeque)
+        } else if segment.ident == "String" {
+            Some(Collection::String)
+        } else {
+            None
+        }
+    }
+
+    /// Appends `value` to `buffer`, both of the collection's type.
+    fn append(self, buffer: &Ident, value: &Ident) -> TokenStream {
+        match self {
+            Collection::String => quote!(#buffer.push_str(&#value)),
+            Collection::Vec | Collection::VecDeque => quote!(#buffer.extend(#value)),
+        }
+    }
+
+    /// How a method call on the collection grows it, if that is all it does.
+    fn growth(self, method: &Ident, args: &Punctuated<Expr, Token![,]>) -> Option<Growth> {
+        let method = method.to_string();
+        let first_is_zero = matches!(
+            args.first(),
+            Some(Expr::Lit(ExprLit {
+                lit: Lit::Int(lit_int),
+                ..
+            })) if lit_int.base10_digits() == "0"
+        );
+
+        let appends: &[&str] = match self {
+            Collection::Vec => &["push", "extend", "extend_from_slice", "append"],
+            Collection::VecDeque => &["push_back", "extend", "append"],
+            Collection::String => &["push", "push_str", "extend"],
+        };
+        let prepends: &[&str] = match self {
+            Collection::Vec => &["insert"],
+            Collection::VecDeque => &["push_front"],
+            Collection::String => &["insert", "insert_str"],
+        };
+
+        if appends.contains(&method.as_str()) && args.len() == 1 {
+            Some(Growth::Append)
+        } else if prepends.contains(&method.as_str())
+            && (args.len() == 1 || args.len() == 2 && first_is_zero)
+        {
+            Some(Growth::Prepend)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a method call appends a whole collection given as its argument,
+    /// as in `v.extend(f(..))`, `v.append(&mut f(..))` or `s.push_str(&f(..))`.
+    fn appends_collection(self, method: &Ident) -> bool {
+        let methods: &[&str] = match self {
+            Collection::Vec => &["extend", "extend_from_slice", "append"],
+            Collection::VecDeque => &["extend", "append"],
+            Collection::String => &["push_str", "extend"],
+        };
+        methods.iter().any(|name| method == name)
+    }
+}
+
+/// Where a statement following the recursive call adds to the result.
+#[derive(Clone, Copy, PartialEq)]
+enum Growth {
+    /// `v.push(x)` and the like, after the value of the call.
+    Append,
+    /// `v.insert(0, x)` and the like, before everything else.
+    Prepend,
+}
+
+/// The pieces built around recursive calls, given a `Vec`, `VecDeque` or
+/// `String` return type, as in `let mut v = vec![x]; v.extend(f(..)); v` or
+/// `format!("{}{}", x, f(..))`.
+///
+/// The calls are made in tail position instead, passing on a single buffer
+/// which the pieces built before the value of each call are appended to as
+/// they come. The pieces built after it are kept on a stack, to be appended
+/// in reverse once the last call has returned, right after its value.
+pub struct Buffer {
+    front: Ident,
+    back: Ident,
+    ty: Type,
+    pub collection: Collection,
+    /// Whether any call is built upon.
+    pub used: bool,
+}
+
+impl Buffer {
+    pub fn new(ty: Option<Type>) -> Option<Self> {
+        let ty = ty?;
+        let collection = Collection::of(&ty)?;
+        let span = Span::mixed_site();
+
+        Some(Buffer {
+            front: Ident::new("front", span),
+            back: Ident::new("back", span),
+            ty,
+            collection,
+            used: false,
+        })
+    }
+
+    /// Declares the buffer and the stack of pieces built after the calls.
+    pub fn declare(&self) -> TokenStream {
+        let Buffer {
+            front, back, ty, ..
+        } = self;
+        if !self.used {
+            return TokenStream::new();
+        }
+
+        quote! {
+            let mut #front: #ty = Default::default();
+            let mut #back: Vec<#ty> = Vec::new();
+        }
+    }
+
+    fn empty(&self) -> TokenStream {
+        let ty = &self.ty;
+        quote!(<#ty as Default>::default())
+    }
+
+    /// Adds the piece built before a recursive call to the buffer, and keeps
+    /// the piece built after it on the stack.
+    fn grow(&self, prefix: Option<TokenStream>, suffix: Option<TokenStream>) -> TokenStream {
+        let Buffer { front, back, .. } = self;
+        let value = Ident::new("value", Span::mixed_site());
+        let append = self.collection.append(front, &value);
+
+        let prefix = prefix.map(|prefix| {
+            quote! {
+                let #value = #prefix;
+                if #front.is_empty() {
+                    #front = #value;
+                } else {
+                    #append;
+                }
+            }
+        });
+        let suffix = suffix.map(|suffix| {
+            quote! {
+                let #value = #suffix;
+                if !#value.is_empty() {
+                    #back.push(#value);
+                }
+            }
+        });
+
+        quote!({ #prefix #suffix })
+    }
+
+    /// Builds the result around the value of the last iteration.
+    pub fn finish(&self, value: &Ident) -> TokenStream {
+        let Buffer { front, back, .. } = self;
+        if !self.used {
+            return quote!(#value);
+        }
+
+        let piece = Ident::new("piece", Span::mixed_site());
+        let append_value = self.collection.append(front, value);
+        let append_piece = self.collection.append(front, &piece);
+
+        quote! {{
+            if #front.is_empty() {
+                #front = #value;
+            } else {
+                #append_value;
+            }
+            while let Some(#piece) = #back.pop() {
+                #append_piece;
+            }
+            #front
+        }}
+    }
+}
+
+impl RecursionTransformer {
+    /// Rewrites the statements of a block in tail position whose value is
+    /// built upon a recursive call, as in
+    /// `let mut v = vec![x]; v.extend(f(..)); v`, returning whether it is.
+    ///
+    /// The variable holds the piece built before the call, and the statements
+    /// after it, which may only add to it, build the piece after the call
+    /// instead, except for those prepending to it.
+    pub fn build_stmts(&mut self, stmts: &mut Vec<Stmt>) -> bool {
+        let rewritten = match self.built_stmts(stmts) {
+            Some(rewritten) => rewritten,
+            None => return false,
+        };
+        *stmts = rewritten;
+        if let Some(buffer) = &mut self.buffer {
+            buffer.used = true;
+        }
+        true
+    }
+
+    fn built_stmts(&self, stmts: &[Stmt]) -> Option<Vec<Stmt>> {
+        let buffer = self.buffer.as_ref()?;
+        if self.item_fn.sig.asyncness.is_some() {
+            return None;
+        }
+
+        let (last, init) = stmts.split_last()?;
+        let var = match last {
+            Stmt::Expr(Expr::Path(ExprPath {
+                qself: None, path, ..
+            })) => path.get_ident()?,
+            _ => return None,
+        };
+        let index = init.iter().rposition(|stmt| match stmt {
+            Stmt::Local(local) => scope::binds(&local.pat, var),
+            _ => false,
+        })?;
+        let local = match &stmts[index] {
+            Stmt::Local(local) => local,
+            _ => return None,
+        };
+        let pat_ident = match &local.pat {
+            Pat::Ident(pat_ident) => pat_ident,
+            Pat::Type(PatType { pat, .. }) => match &**pat {
+                Pat::Ident(pat_ident) => pat_ident,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if pat_ident.ident != *var || pat_ident.by_ref.is_some() || pat_ident.subpat.is_some() {
+            return None;
+        }
+        let (_, value) = local.init.as_ref()?;
+        let rest = &init[index + 1..];
+
+        // The call is either the initial value or appended by a statement, all
+        // of those following which only add to the variable.
+        let (local, before, call, after) = if self.is_recursive_call(value) {
+            let mut local = local.clone();
+            let empty = buffer.empty();
+            local.init = Some((Default::default(), Box::new(parse_quote!(#empty))));
+            (local, &rest[..0], (**value).clone(), rest)
+        } else {
+            let position = rest
+                .iter()
+                .position(|stmt| self.appended_call(stmt, var).is_some())?;
+            let call = self.appended_call(&rest[position], var)?;
+            (
+                local.clone(),
+                &rest[..position],
+                call,
+                &rest[position + 1..],
+            )
+        };
+        if pat_ident.mutability.is_none() && !after.is_empty() {
+            return None;
+        }
+
+        let piece = Ident::new("piece", Span::mixed_site());
+        let mut prepended = vec![];
+        let mut appended = vec![];
+        for stmt in after {
+            let (growth, stmt) = self.growth(stmt, var, &piece)?;
+            match growth {
+                Growth::Prepend => prepended.push(stmt),
+                Growth::Append => appended.push(stmt),
+            }
+        }
+
+        let suffix = if appended.is_empty() {
+            None
+        } else {
+            let empty = buffer.empty();
+            Some(quote!({
+                #[allow(unused_mut)]
+                let mut #piece = #empty;
+                #(#appended)*
+                #piece
+            }))
+        };
+        let grow = buffer.grow(Some(quote!(#var)), suffix);
+
+        let mut rewritten = stmts[..index].to_vec();
+        rewritten.push(Stmt::Local(local));
+        rewritten.extend(before.iter().cloned());
+        rewritten.extend(prepended);
+        rewritten.push(Stmt::Semi(Expr::Verbatim(grow), Default::default()));
+        rewritten.push(Stmt::Expr(call));
+        Some(rewritten)
+    }
+
+    /// The recursive call a statement appends to `var` whole, if any.
+    fn appended_call(&self, stmt: &Stmt, var: &Ident) -> Option<Expr> {
+        let collection = self.buffer.as_ref()?.collection;
+        let expr = match stmt {
+            Stmt::Semi(expr, _) => expr,
+            _ => return None,
+        };
+        let arg = match expr {
+            Expr::MethodCall(ExprMethodCall {
+                receiver,
+                method,
+                turbofish: None,
+                args,
+                ..
+            }) if is_var(receiver, var)
+                && args.len() == 1
+                && collection.appends_collection(method) =>
+            {
+                args.first()?
+            }
+            Expr::AssignOp(ExprAssignOp {
+                left,
+                op: BinOp::AddEq(_),
+                right,
+                ..
+            }) if is_var(left, var) && collection == Collection::String => right,
+            _ => return None,
+        };
+
+        let call = strip_reference(arg);
+        if self.is_recursive_call(call) {
+            Some(call.clone())
+        } else {
+            None
+        }
+    }
+
+    /// How a statement following the recursive call adds to `var`, rewritten
+    /// to add to `piece` instead if it appends.
+    fn growth(&self, stmt: &Stmt, var: &Ident, piece: &Ident) -> Option<(Growth, Stmt)> {
+        let collection = self.buffer.as_ref()?.collection;
+        let mut stmt = stmt.clone();
+        let expr = match &mut stmt {
+            Stmt::Semi(expr, _) => expr,
+            _ => return None,
+        };
+
+        let (growth, receiver, args) = match expr {
+            Expr::MethodCall(ExprMethodCall {
+                receiver,
+                method,
+                turbofish: None,
+                args,
+                ..
+            }) => (
+                collection.growth(method, args)?,
+                receiver,
+                args.to_token_stream(),
+            ),
+            Expr::AssignOp(ExprAssignOp {
+                left,
+                op: BinOp::AddEq(_),
+                right,
+                ..
+            }) if collection == Collection::String => {
+                (Growth::Append, left, right.to_token_stream())
+            }
+            _ => return None,
+        };
+        if !is_var(receiver, var)
+            || mentions(args.clone(), &var.to_string())
+            || self.target.mentioned_in(args)
+        {
+            return None;
+        }
+
+        if growth == Growth::Append {
+            **receiver = parse_quote!(#piece);
+        }
+        Some((growth, stmt))
+    }
+
+    /// Rewrites an expression in tail position built upon a recursive call,
+    /// as in `format!("{}{}", x, f(..))`, `[vec![x], f(..)].concat()` or
+    /// `x + &f(..)`, returning whether it is.
+    pub fn visit_built(&mut self, expr: &mut Expr) -> bool {
+        if self.item_fn.sig.asyncness.is_some() {
+            return false;
+        }
+        let (prefix, call, suffix) = match self.built_expr(expr) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let grow = match &mut self.buffer {
+            Some(buffer) => {
+                buffer.used = true;
+                buffer.grow(prefix, suffix)
+            }
+            None => return false,
+        };
+
+        let mut block = Block {
+            brace_token: Default::default(),
+            stmts: vec![
+                Stmt::Semi(Expr::Verbatim(grow), Default::default()),
+                Stmt::Expr(call),
+            ],
+        };
+        self.visit_stmts(&mut block, true);
+        let stmts = &block.stmts;
+        *expr = Expr::Verbatim(quote!({ #(#stmts)* }));
+
+        true
+    }
+
+    /// The code building the pieces before and after the recursive call of a
+    /// built expression, and the call.
+    fn built_expr(&self, expr: &Expr) -> Option<(Option<TokenStream>, Expr, Option<TokenStream>)> {
+        let buffer = self.buffer.as_ref()?;
+        let collection = buffer.collection;
+
+        match expr {
+            Expr::Macro(ExprMacro { mac, .. })
+                if collection == Collection::String && mac.path.is_ident("format") =>
+            {
+                let parser = Punctuated::<Expr, Token![,]>::parse_terminated;
+                let args = mac.parse_body_with(parser).ok()?;
+                let mut args = args.into_iter();
+                let format = match args.next()? {
+                    Expr::Lit(ExprLit {
+                        lit: Lit::Str(lit_str),
+                        ..
+                    }) => lit_str,
+                    _ => return None,
+                };
+                let args: Vec<_> = args.collect();
+                let index = self.only_call(&args)?;
+                let (before, after) = split_format(&format.value(), index)?;
+                let (args_before, args_after) = (&args[..index], &args[index + 1..]);
+
+                let piece = |format: String, args: &[Expr]| {
+                    if format.is_empty() {
+                        None
+                    } else {
+                        let format = LitStr::new(&format, format_span(&mac.path));
+                        Some(quote!(format!(#format #(, #args)*)))
+                    }
+                };
+                Some((
+                    piece(before, args_before),
+                    args[index].clone(),
+                    piece(after, args_after),
+                ))
+            }
+            Expr::MethodCall(ExprMethodCall {
+                receiver,
+                method,
+                turbofish: None,
+                args,
+                ..
+            }) if method == "concat" && args.is_empty() && collection != Collection::VecDeque => {
+                let elems: Vec<_> = match &**receiver {
+                    Expr::Array(expr_array) => expr_array.elems.iter().cloned().collect(),
+                    _ => return None,
+                };
+                let stripped: Vec<_> = elems
+                    .iter()
+                    .map(|elem| strip_reference(elem).clone())
+                    .collect();
+                let index = self.only_call(&stripped)?;
+
+                let piece = |elems: &[Expr]| {
+                    if elems.is_empty() {
+                        None
+                    } else {
+                        Some(quote!([#(#elems),*].concat()))
+                    }
+                };
+                Some((
+                    piece(&elems[..index]),
+                    stripped[index].clone(),
+                    piece(&elems[index + 1..]),
+                ))
+            }
+            Expr::Binary(ExprBinary {
+                op: BinOp::Add(_), ..
+            }) if collection == Collection::String && self.accumulator.is_none() => {
+                let mut operands = vec![];
+                let mut first = expr;
+                while let Expr::Binary(ExprBinary {
+                    left,
+                    op: BinOp::Add(_),
+                    right,
+                    ..
+                }) = first
+                {
+                    operands.push(&**right);
+                    first = strip_parens(left);
+                }
+                operands.push(first);
+                operands.reverse();
+
+                let stripped: Vec<_> = operands
+                    .iter()
+                    .enumerate()
+                    .map(|(index, operand)| match index {
+                        0 => (*operand).clone(),
+                        _ => strip_reference(operand).clone(),
+                    })
+                    .collect();
+                let index = self.only_call(&stripped)?;
+                // Only string slices can be added to a `String`.
+                if index > 0 && !matches!(operands[index], Expr::Reference(_)) {
+                    return None;
+                }
+
+                let prefix = operands[..index]
+                    .split_first()
+                    .map(|(first, before)| quote!(#first #(+ #before)*));
+                let after = &operands[index + 1..];
+                let empty = buffer.empty();
+                let suffix = if after.is_empty() {
+                    None
+                } else {
+                    Some(quote!(#empty #(+ #after)*))
+                };
+                Some((prefix, stripped[index].clone(), suffix))
+            }
+            _ => None,
+        }
+    }
+
+    /// The position of the only one of `exprs` that is a recursive call, if
+    /// none of the others mentions the function.
+    fn only_call(&self, exprs: &[Expr]) -> Option<usize> {
+        let index = exprs.iter().position(|expr| self.is_recursive_call(expr))?;
+        let others = exprs
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != index);
+        for (_, expr) in others {
+            if self.target.mentioned_in(expr.to_token_stream()) {
+                return None;
+            }
+        }
+        Some(index)
+    }
+}
+
+fn is_var(expr: &Expr, var: &Ident) -> bool {
+    match expr {
+        Expr::Path(expr_path) => expr_path.qself.is_none() && expr_path.path.is_ident(var),
+        _ => false,
+    }
+}
+
+fn strip_reference(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Reference(ExprReference { expr, .. }) => strip_reference(expr),
+        Expr::Paren(ExprParen { expr, .. }) => strip_reference(expr),
+        expr => expr,
+    }
+}
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(expr_paren) => strip_parens(&expr_paren.expr),
+        expr => expr,
+    }
+}
+
+fn format_span(path: &Path) -> Span {
+    path.segments
+        .last()
+        .map_or_else(Span::call_site, |segment| segment.ident.span())
+}
+
+/// Splits a format string around the placeholder of the argument at `index`,
+/// which must be a plain `{}`, while every placeholder takes the next
+/// argument, as neither the pieces nor their arguments could be told apart
+/// otherwise.
+fn split_format(format: &str, index: usize) -> Option<(String, String)> {
+    let mut chars = format.char_indices().peekable();
+    let mut placeholders = 0;
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                chars.next();
+            }
+            '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                chars.next();
+            }
+            '{' => {
+                let mut spec = String::new();
+                let end = loop {
+                    match chars.next()? {
+                        (end, '}') => break end,
+                        (_, c) => spec.push(c),
+                    }
+                };
+                let (argument, format_spec) = match spec.find(':') {
+                    Some(colon) => (&spec[..colon], &spec[colon + 1..]),
+                    None => (spec.as_str(), ""),
+                };
+                if !argument.is_empty() || format_spec.contains(['$', '*']) {
+                    return None;
+                }
+
+                if placeholders == index {
+                    if !spec.is_empty() {
+                        return None;
+                    }
+                    let (before, after) = (&format[..start], &format[end + 1..]);
+                    // Every other placeholder must take the next argument too.
+                    split_format(after, usize::MAX)?;
+                    return Some((before.to_string(), after.to_string()));
+                }
+                placeholders += 1;
+            }
+            _ => {}
+        }
+    }
+
+    if index == usize::MAX {
+        Some((String::new(), String::new()))
+    } else {
+        None
+    }
+}
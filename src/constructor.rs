@@ -0,0 +1,257 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use std::mem;
+use syn::{visit::Visit, *};
+
+use crate::RecursionTransformer;
+
+/// The constructors wrapping recursive calls, as in
+/// `Cons(x, Box::new(f(..)))` or `Node { left, right: Box::new(f(..)) }`.
+///
+/// The calls are made in tail position instead. Each constructor is left with
+/// a hole where the value of the call goes, which the value of the next
+/// iteration fills. The holes are kept on a stack on the heap, as closures
+/// building the value around what they are given, and are filled in reverse
+/// once the last call has returned.
+pub struct Holes {
+    holes: Ident,
+    ty: Type,
+    /// Whether any call is wrapped in a constructor.
+    pub used: bool,
+}
+
+impl Holes {
+    pub fn new(ty: Type) -> Self {
+        Holes {
+            holes: Ident::new("holes", Span::mixed_site()),
+            ty,
+            used: false,
+        }
+    }
+
+    /// Declares the stack of holes, whose type is spelled out through a
+    /// function so that the elided lifetimes of the return type are inferred
+    /// rather than higher-ranked.
+    pub fn declare(&self) -> TokenStream {
+        let Holes { holes, ty, .. } = self;
+        if !self.used {
+            return TokenStream::new();
+        }
+
+        let span = Span::mixed_site();
+        let lifetime = Lifetime::new("'a", span);
+        let value = Ident::new("R", span);
+        quote! {
+            #[allow(clippy::type_complexity)]
+            fn #holes<#lifetime, #value>() -> Vec<Box<dyn FnOnce(#value) -> #value + #lifetime>> {
+                Vec::new()
+            }
+            let mut #holes = #holes::<#ty>();
+        }
+    }
+
+    /// Fills the holes with the value of the last iteration.
+    pub fn finish(&self, value: &Ident) -> TokenStream {
+        let holes = &self.holes;
+        if !self.used {
+            return quote!(#value);
+        }
+
+        let hole = Ident::new("hole", Span::mixed_site());
+        quote! {{
+            let mut #value = #value;
+            while let Some(#hole) = #holes.pop() {
+                #value = #hole(#value);
+            }
+            #value
+        }}
+    }
+}
+
+/// A constructor wrapping a recursive call, split into the operands evaluated
+/// before the call, the constructor with a hole and the call.
+struct Constructed {
+    operands: Vec<(Ident, Expr)>,
+    hole: Expr,
+    call: Expr,
+}
+
+impl RecursionTransformer {
+    /// Rewrites an expression in tail position wrapping a recursive call in
+    /// constructors, returning whether it does.
+    pub fn visit_constructed(&mut self, expr: &mut Expr) -> bool {
+        let holes = match &self.holes {
+            Some(holes) => &holes.holes,
+            None => return false,
+        };
+        let value = Ident::new("value", Span::mixed_site());
+        let Constructed {
+            operands,
+            hole,
+            call,
+        } = match self.constructed(expr, &value) {
+            Some(constructed) => constructed,
+            None => return false,
+        };
+
+        // The operands are evaluated before the call, as they would be.
+        let (names, operands): (Vec<_>, Vec<_>) = operands.into_iter().unzip();
+        let push = quote! {{
+            #(let #names = #operands;)*
+            #holes.push(Box::new(move |#value| #hole));
+        }};
+        if let Some(holes) = &mut self.holes {
+            holes.used = true;
+        }
+
+        let mut block = Block {
+            brace_token: Default::default(),
+            stmts: vec![
+                Stmt::Semi(Expr::Verbatim(push), Default::default()),
+                Stmt::Expr(call),
+            ],
+        };
+        self.visit_stmts(&mut block, true);
+        let stmts = &block.stmts;
+        *expr = Expr::Verbatim(quote!({ #(#stmts)* }));
+
+        true
+    }
+
+    /// Splits an expression wrapping a single recursive call in constructors,
+    /// with `value` in place of the call.
+    fn constructed(&self, expr: &Expr, value: &Ident) -> Option<Constructed> {
+        if self.item_fn.sig.asyncness.is_some() || !is_constructor(expr) {
+            return None;
+        }
+
+        let mut operands = vec![];
+        let mut hole = expr.clone();
+        let call = self.hole(&mut hole, value, &mut operands)?;
+        Some(Constructed {
+            operands,
+            hole,
+            call,
+        })
+    }
+
+    /// Replaces the recursive call an expression wraps in constructors with
+    /// `value`, and the other operands with variables, returning the call.
+    fn hole(
+        &self,
+        expr: &mut Expr,
+        value: &Ident,
+        operands: &mut Vec<(Ident, Expr)>,
+    ) -> Option<Expr> {
+        if self.is_recursive_call(expr) {
+            return Some(mem::replace(expr, parse_quote!(#value)));
+        }
+
+        let fields: Vec<&mut Expr> = match expr {
+            Expr::Call(expr_call) if is_constructor_path(&expr_call.func) => {
+                expr_call.args.iter_mut().collect()
+            }
+            Expr::Struct(expr_struct) => {
+                if let Some(rest) = &expr_struct.rest {
+                    if self.target.mentioned_in(rest.to_token_stream()) {
+                        return None;
+                    }
+                }
+                expr_struct
+                    .fields
+                    .iter_mut()
+                    .map(|field| &mut field.expr)
+                    .collect()
+            }
+            Expr::Paren(expr_paren) => return self.hole(&mut expr_paren.expr, value, operands),
+            _ => return None,
+        };
+
+        // The call is in the only field mentioning the function.
+        let mut call = None;
+        for field in fields {
+            if self.target.mentioned_in(field.to_token_stream()) {
+                if call.is_some() {
+                    return None;
+                }
+                call = Some(self.hole(field, value, operands)?);
+            } else if !is_constant(field) {
+                let name = Ident::new(&format!("field{}", operands.len()), Span::mixed_site());
+                let operand = mem::replace(field, parse_quote!(#name));
+                operands.push((name, operand));
+            }
+        }
+        call
+    }
+}
+
+/// Whether an expression of a block wraps a recursive call in constructors,
+/// which is what holes are needed for.
+pub fn wraps_call(transformer: &RecursionTransformer, block: &Block) -> bool {
+    struct ConstructorFinder<'a> {
+        transformer: &'a RecursionTransformer,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for ConstructorFinder<'_> {
+        fn visit_expr(&mut self, node: &'ast Expr) {
+            if self.found {
+                return;
+            }
+            let value = Ident::new("value", Span::mixed_site());
+            self.found = self.transformer.constructed(node, &value).is_some();
+            visit::visit_expr(self, node);
+        }
+
+        fn visit_expr_closure(&mut self, _node: &'ast ExprClosure) {}
+
+        fn visit_item(&mut self, _node: &'ast Item) {}
+    }
+
+    let mut finder = ConstructorFinder {
+        transformer,
+        found: false,
+    };
+    finder.visit_block(block);
+    finder.found
+}
+
+fn is_constructor(expr: &Expr) -> bool {
+    match expr {
+        Expr::Call(expr_call) => is_constructor_path(&expr_call.func),
+        Expr::Struct(_) => true,
+        Expr::Paren(expr_paren) => is_constructor(&expr_paren.expr),
+        _ => false,
+    }
+}
+
+/// Whether a callee is a tuple struct or variant, going by its capitalised
+/// name, as in `Cons` or `Tree::Node`, or the `new` function of a smart
+/// pointer, as in `Box::new`.
+fn is_constructor_path(func: &Expr) -> bool {
+    let path = match func {
+        Expr::Path(ExprPath { path, .. }) => path,
+        _ => return false,
+    };
+    let mut segments = path.segments.iter().rev();
+    let last = match segments.next() {
+        Some(last) => last.ident.to_string(),
+        None => return false,
+    };
+
+    if last.starts_with(char::is_uppercase) {
+        return true;
+    }
+    match segments.next() {
+        Some(pointer) if last == "new" => ["Box", "Rc", "Arc"]
+            .iter()
+            .any(|name| pointer.ident == name),
+        _ => false,
+    }
+}
+
+/// Whether an operand needs no variable to be evaluated ahead, being a
+/// literal or a path such as a unit variant.
+fn is_constant(expr: &Expr) -> bool {
+    matches!(expr, Expr::Lit(_) | Expr::Path(_))
+}
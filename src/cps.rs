@@ -0,0 +1,636 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use std::mem;
+use syn::*;
+
+use crate::hoist::{is_variable, iterates, makes_call};
+use crate::iterator;
+use crate::let_else::LetElse;
+use crate::scope;
+use crate::tail;
+use crate::utils::SignatureExtensions;
+use crate::RecursionTransformer;
+
+/// The continuations of the calls in progress, given `cps`.
+///
+/// The body is rewritten in continuation-passing style. A recursive call
+/// hands its arguments back to the loop, along with a boxed closure taking its
+/// value and running the rest of the body. The loop pushes the closure onto a
+/// stack on the heap and starts the call. The value a call returns is handed
+/// to the closure on top of the stack, until there is none.
+///
+/// As a continuation is a value rather than a place to return to, calls are
+/// not limited to the expressions leading to the result. The code following an
+/// `if` or a `match` whose branches make calls is bound once, after the
+/// expression, as a continuation joining the branches: each of them hands it
+/// its value, along with the variables the code uses. The body of a `for` loop
+/// making calls runs in a closure for each item, which takes those variables
+/// and hands them on to the next one. A call in any other closure, however, is
+/// made by whatever runs the closure, which waits for its value rather than
+/// taking a continuation, so it is an error.
+pub struct Continuations {
+    step: Ident,
+    call: Ident,
+    start: Ident,
+    join: Ident,
+    each: Ident,
+    stack: Ident,
+}
+
+impl Continuations {
+    pub fn new() -> Self {
+        let span = Span::mixed_site();
+        Continuations {
+            step: Ident::new("Step", span),
+            call: Ident::new("call", span),
+            start: Ident::new("start", span),
+            join: Ident::new("join", span),
+            each: Ident::new("each", span),
+            stack: Ident::new("stack", span),
+        }
+    }
+
+    /// The number of calls in progress, those waiting for their continuation
+    /// and the current one.
+    pub fn depth(&self) -> TokenStream {
+        let stack = &self.stack;
+        quote!(#stack.len() as u64 + 1)
+    }
+
+    /// Declares the steps the body takes, the functions making them and the
+    /// stack.
+    ///
+    /// A step is generic over the value the branches of an expression hand to
+    /// the continuation joining them, which the body as a whole never does.
+    /// The closures are passed to generic functions rather than boxed where
+    /// they are written, so that their parameter types are inferred, from the
+    /// return type given to `call` where it can be named, and from the values
+    /// of the branches for the continuation joining them.
+    pub fn declare(&self) -> TokenStream {
+        let Continuations {
+            step,
+            call,
+            start,
+            join,
+            each,
+            stack,
+        } = self;
+        let span = Span::mixed_site();
+        let lifetime = Lifetime::new("'a", span);
+        let args = Ident::new("A", span);
+        let result = Ident::new("R", span);
+        let joined = Ident::new("V", span);
+        let next = Ident::new("W", span);
+        let function = Ident::new("F", span);
+        let items_type = Ident::new("I", span);
+        let live_type = Ident::new("L", span);
+        let state = Ident::new("state", span);
+        let continuation = Ident::new("continuation", span);
+        let value = Ident::new("value", span);
+        let branch = Ident::new("branch", span);
+        let items = Ident::new("items", span);
+        let item = Ident::new("item", span);
+        let live = Ident::new("live", span);
+        let body = Ident::new("body", span);
+        let generics = quote!(<#lifetime, #args, #result, #joined>);
+        let body_generics = quote!(<#lifetime, #args, #result, std::convert::Infallible>);
+
+        quote! {
+            #[allow(dead_code)]
+            enum #step #generics {
+                Call(#args, Option<Box<dyn FnOnce(#result) -> #step #generics + #lifetime>>),
+                Return(#result),
+                Value(#joined),
+            }
+            #[allow(dead_code)]
+            fn #call<#lifetime, #result, #args, #joined, #function>(
+                #state: #args,
+                #continuation: #function,
+            ) -> #step #generics
+            where
+                #function: FnOnce(#result) -> #step #generics + #lifetime,
+            {
+                #step::Call(#state, Some(Box::new(#continuation)))
+            }
+            fn #start<#lifetime, #args, #result>(
+                #body: impl FnOnce() -> #step #body_generics,
+            ) -> #step #body_generics {
+                #body()
+            }
+            #[allow(dead_code)]
+            fn #join<#lifetime, #args, #result, #joined, #next, #function>(
+                #branch: #step #generics,
+                #continuation: #function,
+            ) -> #step<#lifetime, #args, #result, #next>
+            where
+                #args: #lifetime,
+                #result: #lifetime,
+                #joined: #lifetime,
+                #next: #lifetime,
+                #function: FnOnce(#joined) -> #step<#lifetime, #args, #result, #next> + #lifetime,
+            {
+                match #branch {
+                    #step::Call(#state, Some(#branch)) => {
+                        let #branch = move |#value| #join(#branch(#value), #continuation);
+                        #step::Call(#state, Some(Box::new(#branch)))
+                    }
+                    #step::Call(#state, None) => #step::Call(#state, None),
+                    #step::Return(#value) => #step::Return(#value),
+                    #step::Value(#value) => #continuation(#value),
+                }
+            }
+            #[allow(dead_code)]
+            fn #each<#lifetime, #args, #result, #items_type, #live_type, #function>(
+                mut #items: #items_type,
+                mut #live: #live_type,
+                mut #body: #function,
+            ) -> #step<#lifetime, #args, #result, ((), #live_type)>
+            where
+                #args: #lifetime,
+                #result: #lifetime,
+                #items_type: Iterator + #lifetime,
+                #live_type: #lifetime,
+                #function: FnMut(#items_type::Item, #live_type)
+                        -> #step<#lifetime, #args, #result, ((), #live_type)>
+                    + #lifetime,
+            {
+                while let Some(#item) = #items.next() {
+                    match #body(#item, #live) {
+                        #step::Value(((), #value)) => #live = #value,
+                        #branch => {
+                            return #join(#branch, move |((), #live)| #each(#items, #live, #body));
+                        }
+                    }
+                }
+                #step::Value(((), #live))
+            }
+            let mut #stack = Vec::new();
+        }
+    }
+
+    /// The type of the step the body takes, given the return type of the
+    /// function if it can be named.
+    pub fn step_type(&self, return_type: Option<Type>) -> TokenStream {
+        let step = &self.step;
+        match return_type {
+            Some(return_type) => quote!(: #step<'_, _, #return_type, std::convert::Infallible>),
+            None => TokenStream::new(),
+        }
+    }
+
+    /// Returns from the code running between recursive calls with the value
+    /// of the call in progress.
+    pub fn finish(&self, value: TokenStream) -> TokenStream {
+        let step = &self.step;
+        quote!(return #step::Return(#value))
+    }
+
+    /// Makes the calls of the steps, handing the value of each to the
+    /// continuation on top of the stack, which may make calls of its own, and
+    /// returns it once the stack is empty.
+    pub fn resume(&self, value: &Ident, acc: &Ident, label: &Lifetime) -> TokenStream {
+        let Continuations { step, stack, .. } = self;
+        let span = Span::mixed_site();
+        let next = Ident::new("next", span);
+        let continuation = Ident::new("continuation", span);
+        let returned = Ident::new("value", span);
+
+        quote! {
+            loop {
+                match #value {
+                    #step::Call(#next, #continuation) => {
+                        #stack.extend(#continuation);
+                        #acc = #next;
+                        continue #label;
+                    }
+                    #step::Return(#returned) => match #stack.pop() {
+                        Some(#continuation) => #value = #continuation(#returned),
+                        None => return #returned,
+                    },
+                    #step::Value(#returned) => match #returned {},
+                }
+            }
+        }
+    }
+}
+
+/// What the value of an expression is handed to.
+enum Then<'a> {
+    /// The value is the result of the call.
+    Return,
+    /// The value is bound to `result` for the statements that follow it, the
+    /// value of the last of which is handed on to `then`.
+    Bind {
+        result: Ident,
+        stmts: Vec<Stmt>,
+        /// Whether the function's name is shadowed where the value is bound.
+        shadowed: bool,
+        /// The variables in scope where the value is bound.
+        scope: Vec<Ident>,
+        then: &'a Then<'a>,
+    },
+    /// The value is the value of a branch, handed along with the variables
+    /// `live` to the continuation joining the branches.
+    Join { live: Vec<Ident> },
+}
+
+impl<'a> Then<'a> {
+    fn bind(
+        result: Ident,
+        stmts: Vec<Stmt>,
+        shadowed: bool,
+        scope: Vec<Ident>,
+        then: &'a Then<'a>,
+    ) -> Self {
+        Then::Bind {
+            result,
+            stmts,
+            shadowed,
+            scope,
+            then,
+        }
+    }
+}
+
+impl RecursionTransformer {
+    /// Rewrites the body into code taking the first step of the call, given
+    /// the variables in `scope`.
+    pub fn cps_body(&mut self, block: &Block, scope: Vec<Ident>) -> TokenStream {
+        let mut block = block.clone();
+        iterator::into_loops(self, &mut block);
+        let body = self.cps_stmts(&block.stmts, &Then::Return, scope);
+        let start = &self
+            .continuations
+            .as_ref()
+            .expect("the body is in cps")
+            .start;
+        quote!(#start(move || { #body }))
+    }
+
+    /// Rewrites statements up to the first recursive call, which takes the
+    /// rest of them as its continuation. The value of the last expression is
+    /// handed to `then`.
+    fn cps_stmts(&mut self, stmts: &[Stmt], then: &Then, scope: Vec<Ident>) -> TokenStream {
+        let shadows = self.declares(stmts);
+
+        self.scoped(shadows, |this| {
+            let mut scope = scope;
+            let mut code = TokenStream::new();
+
+            for (index, stmt) in stmts.iter().enumerate() {
+                let rest = &stmts[index + 1..];
+
+                match stmt {
+                    Stmt::Expr(expr) if rest.is_empty() => {
+                        code.extend(this.cps_expr(expr, then, scope));
+                        return code;
+                    }
+                    // Whatever comes after is unreachable.
+                    Stmt::Semi(Expr::Return(ExprReturn { expr, .. }), _) => {
+                        let expr = match expr {
+                            Some(expr) => (**expr).clone(),
+                            None => parse_quote!(()),
+                        };
+                        code.extend(this.cps_expr(&expr, &Then::Return, scope));
+                        return code;
+                    }
+                    Stmt::Item(_) => stmt.to_tokens(&mut code),
+                    _ => {
+                        // The value of an expression followed by others is discarded.
+                        let mut stmt = match stmt.clone() {
+                            Stmt::Expr(expr) => Stmt::Semi(expr, Default::default()),
+                            stmt => stmt,
+                        };
+                        if let Some((result, unit)) = this.hoist_first_stmt(&mut stmt) {
+                            let discarded =
+                                matches!(&stmt, Stmt::Semi(expr, _) if is_variable(expr, &result));
+                            let stmts = if discarded {
+                                rest.to_vec()
+                            } else {
+                                Some(stmt).into_iter().chain(rest.iter().cloned()).collect()
+                            };
+                            let then =
+                                Then::bind(result, stmts, this.shadowed, scope.clone(), then);
+                            code.extend(this.cps_unit(&unit, &then, scope));
+                            return code;
+                        }
+
+                        this.finish_stmt(&mut stmt);
+                        stmt.to_tokens(&mut code);
+
+                        let pat = match &stmt {
+                            Stmt::Local(local) => Some(local.pat.clone()),
+                            Stmt::Semi(Expr::Verbatim(tokens), _) => {
+                                parse2::<LetElse>(tokens.clone())
+                                    .ok()
+                                    .map(|let_else| let_else.pat)
+                            }
+                            _ => None,
+                        };
+                        if let Some(pat) = pat {
+                            this.bind_live(&pat, then, &mut scope);
+                            this.shadowed |= this.binds(&pat);
+                        }
+                    }
+                }
+            }
+
+            code.extend(this.apply(then, quote!(())));
+            code
+        })
+    }
+
+    /// Rewrites an expression whose value is handed to `then`.
+    fn cps_expr(&mut self, expr: &Expr, then: &Then, scope: Vec<Ident>) -> TokenStream {
+        match expr {
+            Expr::If(expr_if) => {
+                let mut expr_if = expr_if.clone();
+                if let Some((result, unit)) = self.hoist_first(&mut expr_if.cond) {
+                    let stmts = vec![Stmt::Expr(Expr::If(expr_if))];
+                    let then = Then::bind(result, stmts, self.shadowed, scope.clone(), then);
+                    return self.cps_unit(&unit, &then, scope);
+                }
+                self.finish_expr(&mut expr_if.cond);
+
+                let ExprIf {
+                    attrs,
+                    cond,
+                    then_branch,
+                    else_branch,
+                    ..
+                } = &expr_if;
+                let shadows = self.cond_binds(cond);
+
+                self.join(then, |this, then| {
+                    let mut then_scope = scope.clone();
+                    if let Expr::Let(expr_let) = &**cond {
+                        this.bind_live(&expr_let.pat, then, &mut then_scope);
+                    }
+                    let then_branch = this.scoped(shadows, |this| {
+                        this.cps_stmts(&then_branch.stmts, then, then_scope)
+                    });
+                    let else_branch = match else_branch {
+                        Some((_, else_branch)) => this.cps_expr(else_branch, then, scope),
+                        None => this.apply(then, quote!(())),
+                    };
+
+                    quote!(#(#attrs)* if #cond { #then_branch } else { #else_branch })
+                })
+            }
+            Expr::Match(expr_match) => {
+                let mut expr_match = expr_match.clone();
+                if let Some((result, unit)) = self.hoist_first(&mut expr_match.expr) {
+                    let stmts = vec![Stmt::Expr(Expr::Match(expr_match))];
+                    let then = Then::bind(result, stmts, self.shadowed, scope.clone(), then);
+                    return self.cps_unit(&unit, &then, scope);
+                }
+                self.finish_expr(&mut expr_match.expr);
+
+                self.join(then, |this, then| {
+                    let ExprMatch { attrs, expr, .. } = &expr_match;
+                    let arms: Vec<_> = expr_match
+                        .arms
+                        .iter()
+                        .map(|arm| {
+                            let Arm { attrs, pat, .. } = arm;
+                            let mut arm_scope = scope.clone();
+                            this.bind_live(pat, then, &mut arm_scope);
+
+                            this.scoped(this.binds(pat), |this| {
+                                let guard = arm.guard.clone().map(|(if_token, mut guard)| {
+                                    this.finish_expr(&mut guard);
+                                    quote!(#if_token #guard)
+                                });
+                                let body = this.cps_expr(&arm.body, then, arm_scope);
+                                quote!(#(#attrs)* #pat #guard => { #body })
+                            })
+                        })
+                        .collect();
+
+                    quote!(#(#attrs)* match #expr { #(#arms)* })
+                })
+            }
+            Expr::Block(ExprBlock {
+                label: None, block, ..
+            }) => {
+                let block = self.cps_stmts(&block.stmts, then, scope);
+                quote!({ #block })
+            }
+            Expr::ForLoop(expr_for_loop) if iterates(self, expr) => {
+                let mut expr_for_loop = expr_for_loop.clone();
+                if let Some((result, unit)) = self.hoist_first(&mut expr_for_loop.expr) {
+                    let stmts = vec![Stmt::Expr(Expr::ForLoop(expr_for_loop))];
+                    let then = Then::bind(result, stmts, self.shadowed, scope.clone(), then);
+                    return self.cps_unit(&unit, &then, scope);
+                }
+                self.finish_expr(&mut expr_for_loop.expr);
+                self.cps_loop(&expr_for_loop, then, scope)
+            }
+            Expr::Paren(expr_paren) => self.cps_expr(&expr_paren.expr, then, scope),
+            Expr::Return(ExprReturn { expr, .. }) => match expr {
+                Some(expr) => self.cps_expr(expr, &Then::Return, scope),
+                None => self.apply(&Then::Return, quote!(())),
+            },
+            // The right operand is only evaluated depending on the left one.
+            Expr::Binary(ExprBinary {
+                left, op, right, ..
+            }) if matches!(op, BinOp::And(_) | BinOp::Or(_)) && makes_call(self, right) => {
+                let expr = match op {
+                    BinOp::And(_) => parse_quote!(if #left { #right } else { false }),
+                    _ => parse_quote!(if #left { true } else { #right }),
+                };
+                self.cps_expr(&expr, then, scope)
+            }
+            _ => {
+                let mut expr = expr.clone();
+                match self.hoist_first(&mut expr) {
+                    // A call whose value is handed on as it is takes the same
+                    // continuation, if any.
+                    Some((result, unit))
+                        if is_variable(&expr, &result) && self.is_recursive_call(&unit) =>
+                    {
+                        self.cps_unit(&unit, then, scope)
+                    }
+                    Some((result, unit)) => {
+                        let stmts = vec![Stmt::Expr(expr)];
+                        let then = Then::bind(result, stmts, self.shadowed, scope.clone(), then);
+                        self.cps_unit(&unit, &then, scope)
+                    }
+                    None => {
+                        self.finish_expr(&mut expr);
+                        self.apply(then, expr.into_token_stream())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewrites a branching expression with `branches`, each of which hands
+    /// its value to `then`.
+    ///
+    /// The statements a bound value is handed to are rewritten once, into a
+    /// continuation joining the branches, rather than once in each of them.
+    fn join(
+        &mut self,
+        then: &Then,
+        branches: impl FnOnce(&mut Self, &Then) -> TokenStream,
+    ) -> TokenStream {
+        let (result, scope) = match then {
+            Then::Bind { result, scope, .. } => (result, scope),
+            _ => return branches(self, then),
+        };
+        let rest = self.rest(then);
+        let live = scope::live(scope.clone(), &rest);
+        let expr = branches(self, &Then::Join { live: live.clone() });
+
+        let join = &self
+            .continuations
+            .as_ref()
+            .expect("the body is in cps")
+            .join;
+        quote! {
+            #join(#expr, move |#[allow(unused_mut)] (#result, (#(mut #live,)*))| { #rest })
+        }
+    }
+
+    /// Rewrites a `for` loop whose body makes recursive calls into steps over
+    /// its items, handing `()` to `then` once they are all done.
+    ///
+    /// The body of each item runs in a closure taking the variables that the
+    /// body and the code following the loop use, and handing them on, with any
+    /// changes, to the next item and finally to that code.
+    fn cps_loop(
+        &mut self,
+        expr_for_loop: &ExprForLoop,
+        then: &Then,
+        scope: Vec<Ident>,
+    ) -> TokenStream {
+        let (done, rest) = match then {
+            Then::Bind { result, .. } => (result.to_token_stream(), self.rest(then)),
+            _ => (quote!(()), self.apply(then, quote!(()))),
+        };
+        let ExprForLoop {
+            pat, expr, body, ..
+        } = expr_for_loop;
+        let live = scope::live(scope.clone(), &quote!(#body #rest));
+
+        let then = Then::Join { live: live.clone() };
+        let mut body_scope = scope;
+        self.bind_live(pat, &then, &mut body_scope);
+        let body = self.scoped(self.binds(pat), |this| {
+            this.cps_stmts(&body.stmts, &then, body_scope)
+        });
+
+        let Continuations { join, each, .. } =
+            self.continuations.as_ref().expect("the body is in cps");
+        let item = Ident::new("item", Span::mixed_site());
+        quote! {
+            #join(
+                #each(
+                    IntoIterator::into_iter(#expr),
+                    (#(#live,)*),
+                    move |#item, #[allow(unused_mut)] (#(mut #live,)*)| {
+                        let #pat = #item;
+                        #body
+                    },
+                ),
+                move |#[allow(unused_mut, unused_variables)] (#done, (#(mut #live,)*))| { #rest },
+            )
+        }
+    }
+
+    /// Adds the variables `pat` binds to `scope`, reporting those shadowing
+    /// the variables a branch or an item hands on to `then`, which it would
+    /// hand on instead.
+    fn bind_live(&mut self, pat: &Pat, then: &Then, scope: &mut Vec<Ident>) {
+        let bindings = scope::bindings(pat);
+        if let Then::Join { live } = then {
+            for binding in &bindings {
+                if live.contains(binding) {
+                    let message = format!(
+                        "`cps` hands `{}` on to the code following this branch or loop, so it \
+                         cannot be shadowed here; rename this binding",
+                        binding
+                    );
+                    self.errors.push(Error::new(binding.span(), message));
+                }
+            }
+        }
+        scope.extend(bindings);
+    }
+
+    /// Makes a hoisted recursive call, or evaluates a hoisted branching
+    /// expression, handing its value to `then`.
+    fn cps_unit(&mut self, unit: &Expr, then: &Then, scope: Vec<Ident>) -> TokenStream {
+        if !self.is_recursive_call(unit) {
+            return self.cps_expr(unit, then, scope);
+        }
+
+        let state = self.next_state(unit).expect("hoisted calls are recursive");
+        let continuations = self.continuations.as_ref().expect("the body is in cps");
+        let Continuations { step, call, .. } = continuations;
+        let step = step.clone();
+        let call = match self.item_fn.sig.nameable_return_type() {
+            Some(return_type) => quote!(#call::<#return_type, _, _, _>),
+            None => quote!(#call),
+        };
+
+        match then {
+            // A tail call has no continuation.
+            Then::Return => quote!(#step::Call(#state, None)),
+            Then::Bind { result, .. } => {
+                if let Some(span) = tail::marker(unit) {
+                    self.report_misplaced(span);
+                }
+                let rest = self.rest(then);
+                quote!(#call(#state, move |#result| { #rest }))
+            }
+            Then::Join { .. } => {
+                if let Some(span) = tail::marker(unit) {
+                    self.report_misplaced(span);
+                }
+                let value = Ident::new("value", Span::mixed_site());
+                let joined = self.apply(then, value.to_token_stream());
+                quote!(#call(#state, move |#value| #joined))
+            }
+        }
+    }
+
+    /// Hands a value without recursive calls to `then`.
+    fn apply(&mut self, then: &Then, value: TokenStream) -> TokenStream {
+        let step = &self
+            .continuations
+            .as_ref()
+            .expect("the body is in cps")
+            .step;
+        match then {
+            Then::Return => quote!(#step::Return(#value)),
+            Then::Bind { result, .. } => {
+                let rest = self.rest(then);
+                quote!(let #result = #value; #rest)
+            }
+            Then::Join { live } => quote!(#step::Value((#value, (#(#live,)*)))),
+        }
+    }
+
+    /// The code of the statements following a bound value.
+    fn rest(&mut self, then: &Then) -> TokenStream {
+        let (result, stmts, shadowed, scope, next) = match then {
+            Then::Bind {
+                result,
+                stmts,
+                shadowed,
+                scope,
+                then,
+            } => (result, stmts, *shadowed, scope, *then),
+            _ => return TokenStream::new(),
+        };
+
+        let mut scope = scope.clone();
+        scope.push(result.clone());
+        let outer = mem::replace(&mut self.shadowed, shadowed);
+        let rest = self.cps_stmts(stmts, next, scope);
+        self.shadowed = outer;
+        rest
+    }
+}
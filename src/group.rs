@@ -0,0 +1,317 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    *,
+};
+
+use crate::args::Args;
+use crate::target::CallTarget;
+use crate::utils::{self, SignatureExtensions};
+use crate::validate::{combine, validate};
+use crate::RecursionTransformer;
+
+/// The functions of a `recursive_group!`.
+pub struct Functions(pub Vec<ItemFn>);
+
+impl Parse for Functions {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut functions = vec![];
+        while !input.is_empty() {
+            functions.push(input.parse()?);
+        }
+        Ok(Functions(functions))
+    }
+}
+
+/// The functions of a `recursive_group!`, which call each other.
+///
+/// Each function runs the same loop, over an enum with a variant holding the
+/// arguments of each function. A call to any of them in tail position sets the
+/// state to its variant, so the functions calling each other take turns in the
+/// loop, whichever of them was called first.
+pub struct Group {
+    members: Vec<Member>,
+    state: Ident,
+}
+
+/// A function of the group, with the variant holding its arguments.
+pub struct Member {
+    target: CallTarget,
+    variant: Ident,
+    types: Vec<Type>,
+}
+
+impl Group {
+    fn new(functions: &[ItemFn], module: Option<&Path>) -> Self {
+        let members = functions
+            .iter()
+            .map(|item_fn| Member {
+                target: CallTarget::new(item_fn, module),
+                variant: Ident::new(&item_fn.sig.ident.to_string(), Span::mixed_site()),
+                types: item_fn.sig.split_inputs().1,
+            })
+            .collect();
+
+        Group {
+            members,
+            state: Ident::new("State", Span::mixed_site()),
+        }
+    }
+
+    pub fn names(&self) -> Vec<&Ident> {
+        self.members
+            .iter()
+            .map(|member| member.target.ident())
+            .collect()
+    }
+
+    /// Resolves the callee of a call expression to a function of the group.
+    ///
+    /// Once a local binding shadows any of the functions, calls by a bare name
+    /// are left as they are for all of them.
+    pub fn resolve(&self, func: &Expr, shadowed: bool) -> Option<&Member> {
+        self.members
+            .iter()
+            .find(|member| member.target.resolve(func, shadowed))
+    }
+
+    /// The loop state for a call to a function of the group, or `None` for any
+    /// other expression.
+    pub fn next_state(&self, expr: &Expr, shadowed: bool) -> Option<TokenStream> {
+        let expr_call = match expr {
+            Expr::Call(expr_call) => expr_call,
+            _ => return None,
+        };
+        let member = self.resolve(&expr_call.func, shadowed)?;
+
+        // The arguments are bound with the types of the parameters, so that they
+        // are coerced as in a call.
+        let Member { variant, types, .. } = member;
+        let args = expr_call.args.iter();
+        let types = if expr_call.args.len() == types.len() {
+            quote!(: (#(#types,)*))
+        } else {
+            TokenStream::new()
+        };
+        let state = &self.state;
+        let next = Ident::new("state", Span::mixed_site());
+
+        Some(quote!({
+            let #next #types = (#(#args,)*);
+            #state::#variant(#next)
+        }))
+    }
+
+    /// Declares the enum of states, generic over the types of the arguments
+    /// so that elided lifetimes in them need no names.
+    fn declare(&self) -> TokenStream {
+        let state = &self.state;
+        let params: Vec<_> = (0..self.members.len())
+            .map(|index| Ident::new(&format!("T{}", index), Span::mixed_site()))
+            .collect();
+        let variants = self.members.iter().map(|member| &member.variant);
+
+        quote! {
+            #[allow(dead_code, non_camel_case_types)]
+            enum #state<#(#params),*> {
+                #(#variants(#params),)*
+            }
+        }
+    }
+
+    /// The type of the loop state, with the types of the arguments spelled
+    /// out.
+    fn state_type(&self) -> TokenStream {
+        let state = &self.state;
+        let types = self.members.iter().map(|member| {
+            let types = &member.types;
+            quote!((#(#types,)*))
+        });
+        quote!(#state<#(#types),*>)
+    }
+}
+
+/// Rejects the functions that cannot share a loop, with an error on the
+/// offending part of each.
+pub fn validate_group(functions: &[ItemFn]) -> Result<()> {
+    let mut errors = vec![];
+
+    if functions.is_empty() {
+        errors.push(Error::new(
+            Span::call_site(),
+            "`recursive_group!` needs at least one function",
+        ));
+    }
+
+    for (index, item_fn) in functions.iter().enumerate() {
+        let sig = &item_fn.sig;
+        if let Err(error) = validate(item_fn) {
+            errors.push(error);
+        }
+
+        if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+            errors.push(Error::new(
+                sig.generics.span(),
+                "the functions of `recursive_group!` cannot be generic, as they share a loop",
+            ));
+        }
+        if let Some(asyncness) = &sig.asyncness {
+            errors.push(Error::new(
+                asyncness.span(),
+                "`recursive_group!` does not support async functions",
+            ));
+        }
+        if let Some(receiver) = sig.receiver() {
+            errors.push(Error::new(
+                receiver.span(),
+                "`recursive_group!` does not support methods",
+            ));
+        }
+        for ty in sig.split_inputs().1 {
+            if !utils::is_nameable(&ty) {
+                errors.push(Error::new(
+                    ty.span(),
+                    "the parameters of functions in `recursive_group!` cannot be `impl Trait`, \
+                     as their types are spelled out in the loop",
+                ));
+            }
+        }
+
+        // A call to any of the functions is the result of the one calling it.
+        let first = &functions[0].sig;
+        if index > 0
+            && sig.output.to_token_stream().to_string()
+                != first.output.to_token_stream().to_string()
+        {
+            let message = format!(
+                "the functions of `recursive_group!` must have the same return type as `{}`",
+                first.ident
+            );
+            errors.push(Error::new(sig.output.span(), message));
+        }
+        if functions[..index]
+            .iter()
+            .any(|other| other.sig.ident == sig.ident)
+        {
+            let message = format!("`{}` is defined more than once", sig.ident);
+            errors.push(Error::new(sig.ident.span(), message));
+        }
+    }
+
+    combine(errors)
+}
+
+/// Rejects the options of an inline module that the functions of a group
+/// calling each other cannot be transformed with, as they share a loop with no
+/// room for what is pending around their calls.
+fn validate_options(functions: &[ItemFn], args: &AttributeArgs) -> Result<()> {
+    let names = functions
+        .iter()
+        .map(|item_fn| format!("`{}`", item_fn.sig.ident))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let errors = args.iter().filter_map(|arg| match arg {
+        NestedMeta::Meta(meta)
+            if ["acc", "accumulate", "cps", "stack"]
+                .iter()
+                .any(|name| meta.path().is_ident(name)) =>
+        {
+            let path = meta.path();
+            let message = format!(
+                "`{}` cannot be applied to {}, which call each other and are transformed \
+                 together in one loop",
+                quote!(#path),
+                names
+            );
+            Some(Error::new(path.span(), message))
+        }
+        _ => None,
+    });
+    combine(errors.collect::<Vec<_>>())
+}
+
+/// Expands the functions of a group, each into the loop of the whole group
+/// starting with its own state, with the options given to the module they are
+/// declared in, if any.
+pub fn expand(functions: &[ItemFn], args: AttributeArgs) -> Result<TokenStream> {
+    validate_group(functions)?;
+    validate_options(functions, &args)?;
+    let parsed = Args::parse(args)?;
+    let module = parsed.module.as_ref();
+
+    let mut transformers = vec![];
+    let mut errors = vec![];
+    for item_fn in functions {
+        let mut transformer = RecursionTransformer::new(item_fn.clone(), parsed.clone());
+        transformer.buffer = None;
+        transformer.holes = None;
+        transformer.group = Some(Group::new(functions, module));
+
+        match transformer.transform_body() {
+            Ok(body) => transformers.push((transformer, body)),
+            Err(error) => errors.push(error),
+        }
+    }
+    combine(errors)?;
+
+    let group = Group::new(functions, module);
+    let declaration = group.declare();
+    let state_type = group.state_type();
+    let result = Ident::new("result", Span::mixed_site());
+
+    let mut entries = vec![];
+    let mut arms = vec![];
+    for (transformer, item_fn) in &transformers {
+        let ItemFn { sig, block, .. } = item_fn;
+        let (sig, input_pats, input_exprs) = transformer.loop_inputs(sig.clone());
+        let state = &group.state;
+        let variant = Ident::new(&sig.ident.to_string(), Span::mixed_site());
+        let result_type = sig.nameable_return_type().map(|ty| quote!(: #ty));
+
+        arms.push(quote! {
+            #state::#variant((#(#input_pats,)*)) => {
+                #[allow(unused_labels, unused_mut)]
+                let mut #result #result_type = #block;
+                #[allow(unreachable_code)]
+                return #result;
+            }
+        });
+        entries.push((sig, quote!(#state::#variant((#(#input_exprs,)*)))));
+    }
+
+    let acc = &transformers[0].0.acc;
+    let label = &transformers[0].0.label;
+    let functions =
+        transformers
+            .iter()
+            .zip(entries)
+            .map(|((transformer, item_fn), (sig, start))| {
+                let ItemFn { attrs, vis, .. } = item_fn;
+                let warnings = &transformer.warnings;
+                let (depth_init, depth_check) = transformer.depth_bound(&sig.ident);
+                quote! {
+                    #(#attrs)*
+                    #vis #sig {
+                        #(#warnings)*
+                        #depth_init
+                        #declaration
+                        let mut #acc: #state_type = #start;
+                        #label: loop {
+                            #depth_check
+                            match #acc {
+                                #(#arms)*
+                            }
+                        }
+                    }
+                }
+            });
+
+    let expanded = quote!(#(#functions)*);
+    if parsed.debug {
+        println!("{}", expanded);
+    }
+    Ok(expanded)
+}
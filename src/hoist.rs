@@ -0,0 +1,505 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use std::mem;
+use syn::{spanned::Spanned, visit::Visit, visit_mut::VisitMut, *};
+
+use crate::let_else::LetElse;
+use crate::tail;
+use crate::warning::Warning;
+use crate::RecursionTransformer;
+
+impl RecursionTransformer {
+    /// Replaces the recursive calls an expression makes on its way to its
+    /// value with variables holding their results, returning the calls in the
+    /// order they are made.
+    pub fn hoist(&mut self, expr: &mut Expr) -> Vec<(Ident, Expr)> {
+        self.hoist_with(expr, false)
+    }
+
+    /// Replaces the first recursive call an expression makes on its way to its
+    /// value, or the first branching expression making some, with a variable
+    /// holding its result.
+    pub fn hoist_first(&mut self, expr: &mut Expr) -> Option<(Ident, Expr)> {
+        self.hoist_with(expr, true).pop()
+    }
+
+    fn hoist_with(&mut self, expr: &mut Expr, first: bool) -> Vec<(Ident, Expr)> {
+        let mut hoister = Hoister {
+            transformer: self,
+            first,
+            calls: vec![],
+            results: self.results,
+        };
+        hoister.visit_expr_mut(expr);
+
+        let Hoister { calls, results, .. } = hoister;
+        self.results = results;
+        calls
+    }
+
+    pub fn hoist_stmt(&mut self, stmt: &mut Stmt) -> Vec<(Ident, Expr)> {
+        match stmt {
+            Stmt::Local(Local {
+                init: Some((_, init)),
+                ..
+            }) => self.hoist(init),
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => self.hoist(expr),
+            _ => vec![],
+        }
+    }
+
+    pub fn hoist_first_stmt(&mut self, stmt: &mut Stmt) -> Option<(Ident, Expr)> {
+        match stmt {
+            Stmt::Local(Local {
+                init: Some((_, init)),
+                ..
+            }) => self.hoist_first(init),
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => self.hoist_first(expr),
+            _ => None,
+        }
+    }
+
+    pub fn finish_expr(&mut self, expr: &mut Expr) {
+        let mut leftovers = Leftovers::new(self);
+        leftovers.visit_expr_mut(expr);
+        let Leftovers {
+            calls,
+            nested_calls,
+            tries,
+            marked,
+            macros,
+            ..
+        } = leftovers;
+        self.report(calls, nested_calls, tries, marked, macros);
+    }
+
+    pub fn finish_stmt(&mut self, stmt: &mut Stmt) {
+        let mut leftovers = Leftovers::new(self);
+        leftovers.visit_stmt_mut(stmt);
+        let Leftovers {
+            calls,
+            nested_calls,
+            tries,
+            marked,
+            macros,
+            ..
+        } = leftovers;
+        self.report(calls, nested_calls, tries, marked, macros);
+    }
+
+    /// Leaves the code running between recursive calls with the value of the
+    /// call in progress.
+    fn leave(&self, value: TokenStream) -> TokenStream {
+        match &self.continuations {
+            Some(continuations) => continuations.finish(value),
+            None => {
+                let label = &self.body_label;
+                quote!(break #label #value)
+            }
+        }
+    }
+
+    /// Reports the recursive calls, the `?` operators and the calls made with
+    /// `tail!` left in the code.
+    ///
+    /// Given `cps`, the calls in closures and async blocks are errors, as the
+    /// code calling them waits for their value, which no continuation can give,
+    /// unless they are given to the iterator methods rewritten into loops.
+    fn report(
+        &mut self,
+        mut calls: Vec<Span>,
+        nested_calls: Vec<Span>,
+        tries: Vec<Span>,
+        marked: Vec<(Span, bool)>,
+        macros: Vec<Macro>,
+    ) {
+        for (span, recursive) in marked {
+            self.report_marked(span, recursive);
+        }
+        macros.iter().for_each(|mac| self.warn_macro(mac));
+
+        let (option, message) = if self.continuations.is_some() {
+            self.errors.extend(nested_calls.into_iter().map(|span| {
+                let message = "`cps` cannot make a recursive call in a closure or an async block \
+                               through continuations, as whatever runs it waits for its value; \
+                               make the call in a `for` loop, or in a closure given to \
+                               `for_each`, `fold`, or `map` followed by a method such as `sum` \
+                               or `collect`, instead";
+                Error::new(span, message)
+            }));
+            (
+                "cps",
+                "this recursive call is not made through continuations, as it is in a `while` \
+                 or `loop`, or in a `for` loop left with `break` or `continue`, so it still \
+                 grows the stack",
+            )
+        } else {
+            calls.extend(nested_calls);
+            (
+                "stack",
+                "this recursive call is not made through the frame stack, as it is in a \
+                 closure, a loop or a branch of an expression whose value is not the \
+                 function's result, so it still grows the stack",
+            )
+        };
+
+        self.warnings.extend(calls.into_iter().map(|span| Warning {
+            span,
+            message: message.to_string(),
+        }));
+        self.errors.extend(tries.into_iter().map(|span| {
+            let message = format!(
+                "`?` can only be used with `{}` in a function returning a `Result` or an \
+                 `Option`",
+                option
+            );
+            Error::new(span, message)
+        }));
+    }
+}
+
+pub fn is_variable(expr: &Expr, ident: &Ident) -> bool {
+    match expr {
+        Expr::Path(expr_path) => expr_path.qself.is_none() && expr_path.path.is_ident(ident),
+        _ => false,
+    }
+}
+
+/// The recursive calls of an expression that are made whenever it is
+/// evaluated, before the expression itself. Those in closures, loops, blocks
+/// and branches are only made conditionally or repeatedly, if ever, and the
+/// arguments of those in blocks may depend on bindings of the block.
+///
+/// Given `first`, only the first call is hoisted, unless a block, a branching
+/// expression, the right operand of `&&` or `||` or, given `cps`, a `for` loop
+/// making calls comes first, in which case it is hoisted whole instead.
+struct Hoister<'a> {
+    transformer: &'a RecursionTransformer,
+    first: bool,
+    calls: Vec<(Ident, Expr)>,
+    results: usize,
+}
+
+impl Hoister<'_> {
+    fn push(&mut self, node: &mut Expr) {
+        let result = Ident::new(&format!("result{}", self.results), Span::mixed_site());
+        self.results += 1;
+        let expr = mem::replace(node, parse_quote!(#result));
+        self.calls.push((result, expr));
+    }
+}
+
+impl VisitMut for Hoister<'_> {
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        if self.first && !self.calls.is_empty() {
+            return;
+        }
+
+        match node {
+            Expr::Block(ExprBlock { label: None, .. }) if self.first => {}
+            Expr::ForLoop(_) if self.first && iterates(self.transformer, node) => {}
+            Expr::Async(_)
+            | Expr::Block(_)
+            | Expr::Break(_)
+            | Expr::Closure(_)
+            | Expr::ForLoop(_)
+            | Expr::Loop(_)
+            | Expr::Macro(_)
+            | Expr::Return(_)
+            | Expr::TryBlock(_)
+            | Expr::Unsafe(_)
+            | Expr::Verbatim(_)
+            | Expr::While(_) => return,
+            Expr::If(expr_if) => self.visit_expr_mut(&mut expr_if.cond),
+            Expr::Match(expr_match) => self.visit_expr_mut(&mut expr_match.expr),
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::And(_) | BinOp::Or(_),
+                ..
+            }) => self.visit_expr_mut(left),
+            _ => visit_mut::visit_expr_mut(self, node),
+        }
+
+        // A call among the arguments of another is made first.
+        if self.first && !self.calls.is_empty() {
+            return;
+        }
+
+        let branches = matches!(
+            node,
+            Expr::Block(_)
+                | Expr::If(_)
+                | Expr::Match(_)
+                | Expr::Binary(ExprBinary {
+                    op: BinOp::And(_) | BinOp::Or(_),
+                    ..
+                })
+        );
+        if self.transformer.is_recursive_call(node)
+            || self.first && branches && makes_call(self.transformer, node)
+            || self.first && iterates(self.transformer, node)
+        {
+            self.push(node);
+        }
+    }
+}
+
+/// Whether an expression makes a recursive call other than in a closure, a
+/// loop or a labelled block, or in a `for` loop making its calls through
+/// continuations.
+pub fn makes_call(transformer: &RecursionTransformer, expr: &Expr) -> bool {
+    struct CallFinder<'a> {
+        transformer: &'a RecursionTransformer,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for CallFinder<'_> {
+        fn visit_expr(&mut self, node: &'ast Expr) {
+            match node {
+                Expr::Block(ExprBlock { label: None, .. }) => {}
+                Expr::ForLoop(_) if iterates(self.transformer, node) => {
+                    self.found = true;
+                    return;
+                }
+                Expr::Async(_)
+                | Expr::Block(_)
+                | Expr::Closure(_)
+                | Expr::ForLoop(_)
+                | Expr::Loop(_)
+                | Expr::Macro(_)
+                | Expr::TryBlock(_)
+                | Expr::Unsafe(_)
+                | Expr::Verbatim(_)
+                | Expr::While(_) => return,
+                _ => {}
+            }
+            self.found |= self.transformer.is_recursive_call(node);
+            visit::visit_expr(self, node);
+        }
+
+        fn visit_item(&mut self, _node: &'ast Item) {}
+    }
+
+    let mut finder = CallFinder {
+        transformer,
+        found: false,
+    };
+    finder.visit_expr(expr);
+    finder.found
+}
+
+/// Whether an expression is a `for` loop whose body makes recursive calls,
+/// given `cps`, which makes them through continuations unless `break` or
+/// `continue` leave the body.
+pub fn iterates(transformer: &RecursionTransformer, expr: &Expr) -> bool {
+    let expr_for_loop = match expr {
+        Expr::ForLoop(expr_for_loop) if transformer.continuations.is_some() => expr_for_loop,
+        _ => return false,
+    };
+    let body = Expr::Block(ExprBlock {
+        attrs: vec![],
+        label: None,
+        block: expr_for_loop.body.clone(),
+    });
+    makes_call(transformer, &body) && !leaves(&expr_for_loop.body)
+}
+
+/// Whether `break` or `continue` leave the body of a loop, rather than a loop
+/// or a labelled block nested in it.
+fn leaves(body: &Block) -> bool {
+    struct ExitFinder {
+        /// The labels of the loops and blocks nested in the body.
+        labels: Vec<Lifetime>,
+        /// The number of loops nested in the body.
+        loops: usize,
+        found: bool,
+    }
+
+    impl ExitFinder {
+        fn exits(&self, label: &Option<Lifetime>) -> bool {
+            match label {
+                Some(label) => !self.labels.contains(label),
+                None => self.loops == 0,
+            }
+        }
+    }
+
+    impl<'ast> Visit<'ast> for ExitFinder {
+        fn visit_expr(&mut self, node: &'ast Expr) {
+            let (label, is_loop) = match node {
+                Expr::Closure(_) | Expr::Async(_) => return,
+                Expr::Break(ExprBreak { label, .. })
+                | Expr::Continue(ExprContinue { label, .. }) => {
+                    self.found |= self.exits(label);
+                    (None, false)
+                }
+                Expr::ForLoop(ExprForLoop { label, .. })
+                | Expr::Loop(ExprLoop { label, .. })
+                | Expr::While(ExprWhile { label, .. }) => (label.as_ref(), true),
+                Expr::Block(ExprBlock { label, .. }) => (label.as_ref(), false),
+                _ => (None, false),
+            };
+
+            let labels = self.labels.len();
+            self.labels.extend(label.map(|label| label.name.clone()));
+            self.loops += is_loop as usize;
+            visit::visit_expr(self, node);
+            self.loops -= is_loop as usize;
+            self.labels.truncate(labels);
+        }
+
+        fn visit_item(&mut self, _node: &'ast Item) {}
+    }
+
+    let mut finder = ExitFinder {
+        labels: vec![],
+        loops: 0,
+        found: false,
+    };
+    finder.visit_block(body);
+    finder.found
+}
+
+/// Adapts the code left between the recursive calls to running in a frame or
+/// a continuation: `return` and `?` leave it rather than the function, and the
+/// recursive calls that could not be hoisted are found to warn about.
+struct Leftovers<'a> {
+    transformer: &'a RecursionTransformer,
+    /// The variant `?` returns on failure, `Err` or `None`.
+    failure: Option<&'static str>,
+    nested: bool,
+    calls: Vec<Span>,
+    /// The recursive calls in closures and async blocks.
+    nested_calls: Vec<Span>,
+    tries: Vec<Span>,
+    /// The calls made with `tail!`, and whether they are recursive calls.
+    marked: Vec<(Span, bool)>,
+    /// The macro invocations, whose recursive calls are left as they are.
+    macros: Vec<Macro>,
+}
+
+impl<'a> Leftovers<'a> {
+    fn new(transformer: &'a RecursionTransformer) -> Self {
+        let return_type = match &transformer.item_fn.sig.output {
+            ReturnType::Type(_, ty) => match &**ty {
+                Type::Path(type_path) => type_path.path.segments.last(),
+                _ => None,
+            },
+            ReturnType::Default => None,
+        };
+        let failure = match return_type {
+            Some(segment) if segment.ident == "Result" => Some("Err"),
+            Some(segment) if segment.ident == "Option" => Some("None"),
+            _ => None,
+        };
+
+        Leftovers {
+            transformer,
+            failure,
+            nested: false,
+            calls: vec![],
+            nested_calls: vec![],
+            tries: vec![],
+            marked: vec![],
+            macros: vec![],
+        }
+    }
+}
+
+impl VisitMut for Leftovers<'_> {
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        match node {
+            Expr::Closure(_) | Expr::Async(_) => {
+                let outer = mem::replace(&mut self.nested, true);
+                visit_mut::visit_expr_mut(self, node);
+                self.nested = outer;
+            }
+            Expr::Return(ExprReturn { expr, .. }) if !self.nested => {
+                if let Some(expr) = expr {
+                    self.visit_expr_mut(expr);
+                }
+                let value = match expr {
+                    Some(expr) => expr.into_token_stream(),
+                    None => quote!(()),
+                };
+                *node = Expr::Verbatim(self.transformer.leave(value));
+            }
+            Expr::Try(ExprTry {
+                expr,
+                question_token,
+                ..
+            }) if !self.nested => {
+                self.visit_expr_mut(expr);
+
+                let value = Ident::new("value", Span::mixed_site());
+                *node = match self.failure {
+                    Some("Err") => {
+                        let failure = self.transformer.leave(quote!(Err(From::from(#value))));
+                        Expr::Verbatim(quote! {
+                            match #expr {
+                                Ok(#value) => #value,
+                                Err(#value) => #failure,
+                            }
+                        })
+                    }
+                    Some(_) => {
+                        let failure = self.transformer.leave(quote!(None));
+                        Expr::Verbatim(quote! {
+                            match #expr {
+                                Some(#value) => #value,
+                                None => #failure,
+                            }
+                        })
+                    }
+                    None => {
+                        self.tries.push(question_token.span());
+                        return;
+                    }
+                };
+            }
+            Expr::Macro(expr_macro) => self.macros.push(expr_macro.mac.clone()),
+            _ => visit_mut::visit_expr_mut(self, node),
+        }
+
+        if let Some(span) = tail::unmark(node) {
+            let recursive = self.transformer.is_recursive_call(node);
+            self.marked.push((span, recursive));
+            return;
+        }
+
+        let span = match &*node {
+            Expr::Call(expr_call) if self.transformer.is_recursive_call(node) => {
+                expr_call.func.span()
+            }
+            Expr::MethodCall(expr_method_call) if self.transformer.is_recursive_call(node) => {
+                expr_method_call.method.span()
+            }
+            _ => return,
+        };
+        if self.nested {
+            self.nested_calls.push(span);
+        } else {
+            self.calls.push(span);
+        }
+    }
+
+    fn visit_stmt_mut(&mut self, node: &mut Stmt) {
+        match node {
+            Stmt::Semi(Expr::Verbatim(tokens), _) => {
+                if let Ok(mut let_else) = parse2::<LetElse>(tokens.clone()) {
+                    self.visit_expr_mut(&mut let_else.init);
+                    self.visit_block_mut(&mut let_else.diverge);
+                    *tokens = let_else.to_token_stream();
+                }
+            }
+            Stmt::Item(Item::Macro(ItemMacro {
+                ident: None, mac, ..
+            })) => self.macros.push(mac.clone()),
+            _ => visit_mut::visit_stmt_mut(self, node),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _node: &mut Item) {
+        // Nested items are functions of their own.
+    }
+}
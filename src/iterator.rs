@@ -0,0 +1,164 @@
+use proc_macro2::Span;
+use std::mem;
+use syn::{visit::Visit, visit_mut::VisitMut, *};
+
+use crate::RecursionTransformer;
+
+/// The iterator methods consuming every item, which the values of a `map` can
+/// be gathered for beforehand.
+const CONSUMERS: &[&str] = &["collect", "count", "last", "max", "min", "product", "sum"];
+
+/// Rewrites the closures making recursive calls that are given to iterator
+/// methods into `for` loops, given `cps`, as whatever runs a closure waits for
+/// its value, while the body of a loop can make its calls through
+/// continuations.
+///
+/// `for_each(|x| ..)` becomes a loop over the items, and `fold(init, |a, x| ..)`
+/// a loop updating the accumulator. `map(|x| ..)` followed by a method
+/// consuming every item, such as `sum` or `collect`, becomes a loop gathering
+/// the values in a vector, whose items the method then consumes. Closures that
+/// `return` or use `?` are left as they are, as those would leave the function
+/// from a loop.
+pub fn into_loops(transformer: &RecursionTransformer, block: &mut Block) {
+    LoopRewriter { transformer }.visit_block_mut(block);
+}
+
+struct LoopRewriter<'a> {
+    transformer: &'a RecursionTransformer,
+}
+
+impl LoopRewriter<'_> {
+    /// The loop taking the place of a method call given a closure, if any.
+    fn rewrite(&self, expr: &Expr) -> Option<Expr> {
+        let consumer = match expr {
+            Expr::MethodCall(expr_method_call) => expr_method_call,
+            _ => return None,
+        };
+        let span = Span::mixed_site();
+        let item = Ident::new("item", span);
+        let receiver = items(&consumer.receiver);
+
+        match (consumer.method.to_string().as_str(), consumer.args.len()) {
+            ("for_each", 1) => {
+                let (pats, body) = self.closure(&consumer.args[0], 1)?;
+                let pat = pats[0];
+                Some(parse_quote! {
+                    for #item in #receiver {
+                        let #pat = #item;
+                        #body;
+                    }
+                })
+            }
+            ("fold", 2) => {
+                let (pats, body) = self.closure(&consumer.args[1], 2)?;
+                let (acc, pat) = (pats[0], pats[1]);
+                let init = &consumer.args[0];
+                let folded = Ident::new("folded", span);
+                Some(parse_quote!({
+                    let mut #folded = #init;
+                    for #item in #receiver {
+                        let #acc = #folded;
+                        let #pat = #item;
+                        #folded = #body;
+                    }
+                    #folded
+                }))
+            }
+            (method, 0) if CONSUMERS.contains(&method) => {
+                let map = match receiver {
+                    Expr::MethodCall(map) if map.method == "map" && map.args.len() == 1 => map,
+                    _ => return None,
+                };
+                let (pats, body) = self.closure(&map.args[0], 1)?;
+                let pat = pats[0];
+                let receiver = items(&map.receiver);
+                let mapped = Ident::new("mapped", span);
+                let method = &consumer.method;
+                let turbofish = &consumer.turbofish;
+                Some(parse_quote!({
+                    let mut #mapped = Vec::new();
+                    for #item in #receiver {
+                        let #pat = #item;
+                        #mapped.push(#body);
+                    }
+                    #mapped.into_iter().#method #turbofish()
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// The parameters and the body of a closure taking `inputs` parameters
+    /// whose body can run in a loop instead, as it makes a recursive call and
+    /// neither `return` nor `?` leave it.
+    fn closure<'e>(&self, expr: &'e Expr, inputs: usize) -> Option<(Vec<&'e Pat>, &'e Expr)> {
+        let closure = match expr {
+            Expr::Closure(closure)
+                if closure.asyncness.is_none() && closure.inputs.len() == inputs =>
+            {
+                closure
+            }
+            _ => return None,
+        };
+
+        let mut finder = Finder {
+            transformer: self.transformer,
+            nested: false,
+            calls: false,
+            leaves: false,
+        };
+        finder.visit_expr(&closure.body);
+        if !finder.calls || finder.leaves {
+            return None;
+        }
+        Some((closure.inputs.iter().collect(), &closure.body))
+    }
+}
+
+/// The items a loop goes over, without the parentheses a method call needs.
+fn items(receiver: &Expr) -> &Expr {
+    match receiver {
+        Expr::Paren(expr_paren) => &expr_paren.expr,
+        _ => receiver,
+    }
+}
+
+impl VisitMut for LoopRewriter<'_> {
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        visit_mut::visit_expr_mut(self, node);
+        if let Some(expr) = self.rewrite(node) {
+            *node = expr;
+        }
+    }
+
+    fn visit_item_mut(&mut self, _node: &mut Item) {
+        // Nested items are functions of their own.
+    }
+}
+
+/// Finds the recursive calls in the body of a closure, and the `return` and
+/// `?` leaving it.
+struct Finder<'a> {
+    transformer: &'a RecursionTransformer,
+    nested: bool,
+    calls: bool,
+    leaves: bool,
+}
+
+impl<'ast> Visit<'ast> for Finder<'_> {
+    fn visit_expr(&mut self, node: &'ast Expr) {
+        match node {
+            Expr::Closure(_) | Expr::Async(_) => {
+                let outer = mem::replace(&mut self.nested, true);
+                visit::visit_expr(self, node);
+                self.nested = outer;
+                return;
+            }
+            Expr::Return(_) | Expr::Try(_) => self.leaves |= !self.nested,
+            _ => self.calls |= self.transformer.is_recursive_call(node),
+        }
+        visit::visit_expr(self, node);
+    }
+
+    fn visit_item(&mut self, _node: &'ast Item) {}
+}
@@ -0,0 +1,79 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    *,
+};
+
+/// A `let pat = init else { .. };` statement.
+///
+/// syn only parses these into verbatim tokens, which hide the `return`
+/// expressions of the `else` block as well as the bindings of the pattern.
+pub struct LetElse {
+    pub attrs: Vec<Attribute>,
+    pub let_token: Token![let],
+    pub pat: Pat,
+    pub eq_token: Token![=],
+    pub init: Expr,
+    pub else_token: Token![else],
+    pub diverge: Block,
+}
+
+impl Parse for LetElse {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let let_token = input.parse()?;
+
+        let leading_vert: Option<Token![|]> = input.parse()?;
+        let mut pat: Pat = input.parse()?;
+        if leading_vert.is_some() || input.peek(Token![|]) {
+            let mut cases = punctuated::Punctuated::new();
+            cases.push_value(pat);
+            while input.peek(Token![|]) {
+                cases.push_punct(input.parse()?);
+                cases.push_value(input.parse()?);
+            }
+            pat = Pat::Or(PatOr {
+                attrs: vec![],
+                leading_vert,
+                cases,
+            });
+        }
+        if input.peek(Token![:]) {
+            pat = Pat::Type(PatType {
+                attrs: vec![],
+                pat: Box::new(pat),
+                colon_token: input.parse()?,
+                ty: input.parse()?,
+            });
+        }
+
+        Ok(LetElse {
+            attrs,
+            let_token,
+            pat,
+            eq_token: input.parse()?,
+            init: input.parse()?,
+            else_token: input.parse()?,
+            diverge: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for LetElse {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let LetElse {
+            attrs,
+            let_token,
+            pat,
+            eq_token,
+            init,
+            else_token,
+            diverge,
+        } = self;
+
+        tokens.extend(quote! {
+            #(#attrs)* #let_token #pat #eq_token #init #else_token #diverge
+        });
+    }
+}
@@ -1,29 +1,108 @@
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
-use syn::{fold::Fold, visit_mut::VisitMut, *};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned, ToTokens};
+use std::mem;
+use syn::{fold::Fold, spanned::Spanned, visit_mut::VisitMut, *};
 
+mod accumulate;
+mod args;
+mod associated;
+mod buffer;
+mod constructor;
+mod cps;
+mod group;
+mod hoist;
+mod iterator;
+mod let_else;
+mod module;
+mod receiver;
+mod scope;
+mod stack;
+mod tail;
+mod target;
 mod utils;
+mod validate;
+mod warning;
 
+use crate::accumulate::{Accumulator, Operation, Side};
+use crate::args::{Args, HiddenParam};
+use crate::buffer::Buffer;
+use crate::constructor::Holes;
+use crate::cps::Continuations;
+use crate::group::{Functions, Group};
+use crate::let_else::LetElse;
+use crate::receiver::{ReceiverKind, ReceiverRenamer};
+use crate::stack::Frames;
+use crate::target::CallTarget;
 use crate::utils::SignatureExtensions;
+use crate::validate::{combine, validate, validate_const};
+use crate::warning::Warning;
 
 #[proc_macro_attribute]
-pub fn recursive(_attr: TokenStream, item_fn: TokenStream) -> TokenStream {
-    let item_fn = parse_macro_input!(item_fn as ItemFn);
-    let mut transformer = RecursionTransformer::new(item_fn);
-    let item_fn = transformer.transform_item_fn();
+pub fn recursive(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = TokenStream::from(tail::rewrite_become(item.into()));
+    let item = parse_macro_input!(item as Item);
 
-    // println!("{}", quote! { #item_fn });
+    let expanded = match &item {
+        Item::Fn(item_fn) => expand(args, item_fn.clone()).map(|item_fn| quote!(#item_fn)),
+        Item::Mod(item_mod) => {
+            module::expand(args, item_mod.clone()).map(|item_mod| quote!(#item_mod))
+        }
+        Item::Impl(item_impl) => {
+            associated::expand_impl(args, item_impl.clone()).map(|item_impl| quote!(#item_impl))
+        }
+        Item::Trait(item_trait) => {
+            associated::expand_trait(args, item_trait.clone()).map(|item_trait| quote!(#item_trait))
+        }
+        _ => Err(Error::new(
+            item.span(),
+            "`#[recursive]` can only be applied to functions, inline modules, `impl` blocks and \
+             traits",
+        )),
+    };
+
+    TokenStream::from(expanded_or_kept(expanded, &item))
+}
+
+#[proc_macro]
+pub fn recursive_group(input: TokenStream) -> TokenStream {
+    let input = TokenStream::from(tail::rewrite_become(input.into()));
+    let Functions(functions) = parse_macro_input!(input as Functions);
+
+    let expanded = group::expand(&functions, vec![]);
+    TokenStream::from(expanded_or_kept(expanded, quote!(#(#functions)*)))
+}
+
+/// The expansion of an item or, if it fails, the errors followed by the item
+/// as it is, so that its uses do not fail as well.
+fn expanded_or_kept(expanded: Result<impl ToTokens>, item: impl ToTokens) -> TokenStream2 {
+    match expanded {
+        Ok(expanded) => expanded.into_token_stream(),
+        Err(error) => {
+            let error = error.to_compile_error();
+            let item = tail::strip_markers(item.into_token_stream());
+            quote!(#error #item)
+        }
+    }
+}
+
+fn expand(args: AttributeArgs, item_fn: ItemFn) -> Result<ItemFn> {
+    let args = Args::parse(args)?;
+    validate(&item_fn)?;
+
+    let debug = args.debug;
+    let mut transformer = RecursionTransformer::new(item_fn, args);
+    let item_fn = transformer.transform_item_fn()?;
+
+    if debug {
+        println!("{}", quote! { #item_fn });
+    }
 
-    TokenStream::from(quote!(#item_fn))
+    Ok(item_fn)
 }
 
 macro_rules! verbatim {
-    (some, $($tokens:tt)*) => {
-        Some(Box::new(Expr::Verbatim(quote! { $($tokens)* })))
-    };
-    (boxed, $($tokens:tt)*) => {
-        Box::new(Expr::Verbatim(quote! { $($tokens)* }))
-    };
     ($($tokens:tt)*) => {
         Expr::Verbatim(quote! { $($tokens)* })
     };
@@ -31,31 +110,145 @@ macro_rules! verbatim {
 
 struct RecursionTransformer {
     item_fn: ItemFn,
+    args: Args,
+    /// Loop state holding the arguments of the current iteration.
+    acc: Ident,
+    /// Label of the loop driving the iterations.
+    label: Lifetime,
+    /// Label of the block holding the body, which is left instead of returning
+    /// when the result is combined with accumulated operands, or handed to the
+    /// frame on top of the stack.
+    body_label: Lifetime,
+    /// The operands pending around recursive calls, given `accumulate`.
+    accumulator: Option<Accumulator>,
+    /// The pieces built around recursive calls, given a `Vec`, `VecDeque` or
+    /// `String` return type and none of the options transforming the body.
+    buffer: Option<Buffer>,
+    /// The constructors wrapping recursive calls, given none of the options
+    /// transforming the body.
+    holes: Option<Holes>,
+    /// The `?` operators of the body, which return early without the pending
+    /// operands.
+    tries: Vec<Span>,
+    /// The frames of the calls in progress, given `stack`.
+    frames: Option<Frames>,
+    /// The continuations of the calls in progress, given `cps`.
+    continuations: Option<Continuations>,
+    /// The functions of the `recursive_group!` the function belongs to, whose
+    /// calls are eliminated as well.
+    group: Option<Group>,
+    /// The number of variables bound to the results of hoisted calls so far.
+    results: usize,
+    /// The parameters hidden from the signature, by their position among the
+    /// parameters other than the receiver, with their initial value.
+    hidden: Vec<(usize, Expr)>,
+    /// Name the receiver is rebound to, if the function is a method.
+    receiver: Option<Ident>,
+    receiver_kind: Option<ReceiverKind>,
+    target: CallTarget,
+    /// Whether the function's name is shadowed at the current point of the body.
+    shadowed: bool,
+    /// Whether the visitor is inside a closure or an async block, where
+    /// `return` does not leave the function.
+    nested: bool,
+    /// Whether the expression about to be visited is in tail position.
+    tail: bool,
+    /// The loops and labelled blocks around the expression being visited.
+    breakables: Vec<Breakable>,
+    warnings: Vec<Warning>,
+    errors: Vec<Error>,
+}
+
+/// A loop or labelled block, which `break` expressions may target.
+struct Breakable {
+    label: Option<Lifetime>,
+    is_loop: bool,
+    /// Whether the loop or block is in tail position.
+    tail: bool,
 }
 
 impl Fold for RecursionTransformer {
     fn fold_item_fn(&mut self, item_fn: ItemFn) -> ItemFn {
         let ItemFn { sig, block, .. } = item_fn;
 
-        let fn_inner = format_ident!("{}_inner", sig.ident);
-        let (input_pats, input_types) = sig.split_inputs();
-        let return_type = sig.extract_return_type();
+        let acc = &self.acc;
+        let label = &self.label;
+        let warnings = &self.warnings;
+        let (sig, input_pats, input_exprs) = self.loop_inputs(sig);
 
-        let block = parse_quote! {{
-            enum Action<C, R> {
-                Continue(C),
-                Return(R),
-            }
+        // The result is bound with the declared return type, so that it is still
+        // coerced to it, e.g. `Option<&String>` to `Option<&str>`. Elided
+        // lifetimes in it are simply inferred.
+        let mut result_type = sig.nameable_return_type().map(|ty| quote!(: #ty));
 
-            fn #fn_inner((#(#input_pats),*): (#(#input_types),*))
-                -> Action<(#(#input_types),*), #return_type> #block
+        // The body runs inline rather than in a nested function, so `Self`, the
+        // generic parameters and any `impl Trait` in the signature stay in scope
+        // and the parameter and return types never need to be spelled out.
+        let (depth_init, depth_check) = self.depth_bound(&sig.ident);
 
-            let mut acc = (#(#input_pats),*);
-            loop {
-                match #fn_inner(acc) {
-                    Action::Return(r) => return r,
-                    Action::Continue(c) => acc = c,
-                }
+        // With accumulators, a buffer, holes or frames, returning from the body
+        // leaves a labelled block instead, so that the result is combined with
+        // the pending operands or pieces, fills the holes, or is handed to the
+        // frame on top of the stack. With
+        // continuations, the body is a step to take instead of the result.
+        let result = Ident::new("result", Span::mixed_site());
+        let body_label = &self.body_label;
+        let (declarations, body, finish) = match (&self.accumulator, &self.frames) {
+            (None, None) if self.continuations.is_some() => {
+                let continuations = self.continuations.as_ref().expect("checked above");
+                result_type = Some(continuations.step_type(sig.nameable_return_type()));
+                (
+                    Some(continuations.declare()),
+                    quote!(#block),
+                    continuations.resume(&result, acc, label),
+                )
+            }
+            (None, None) if self.buffer.is_some() => {
+                let buffer = self.buffer.as_ref().expect("checked above");
+                let finish = buffer.finish(&result);
+                (
+                    Some(buffer.declare()),
+                    quote!(#body_label: #block),
+                    quote!(return #finish;),
+                )
+            }
+            (None, None) if self.holes.is_some() => {
+                let holes = self.holes.as_ref().expect("checked above");
+                let finish = holes.finish(&result);
+                (
+                    Some(holes.declare()),
+                    quote!(#body_label: #block),
+                    quote!(return #finish;),
+                )
+            }
+            (Some(accumulator), _) => {
+                let finish = accumulator.finish(&result);
+                (
+                    Some(accumulator.declare()),
+                    quote!(#body_label: #block),
+                    quote!(return #finish;),
+                )
+            }
+            (None, Some(frames)) => (
+                Some(frames.declare()),
+                quote!(#body_label: #block),
+                frames.resume(body_label),
+            ),
+            (None, None) => (None, quote!(#block), quote!(return #result;)),
+        };
+
+        let block = parse_quote! {{
+            #(#warnings)*
+            #depth_init
+            #declarations
+            let mut #acc = (#(#input_exprs,)*);
+            #label: loop {
+                #depth_check
+                let (#(#input_pats,)*) = #acc;
+                #[allow(unused_labels, unused_mut)]
+                let mut #result #result_type = #body;
+                #[allow(unreachable_code)]
+                #finish
             }
         }};
 
@@ -65,139 +258,1071 @@ impl Fold for RecursionTransformer {
             ..item_fn
         }
     }
+}
 
-    fn fold_expr_return(&mut self, expr_return: ExprReturn) -> ExprReturn {
-        let ExprReturn { expr, .. } = expr_return;
+impl RecursionTransformer {
+    fn new(item_fn: ItemFn, args: Args) -> Self {
+        let span = Span::mixed_site();
+        let receiver = item_fn
+            .sig
+            .receiver()
+            .map(|_| Ident::new("__recursive_self", span));
+        let receiver_kind = ReceiverKind::of(&item_fn.sig);
+        let target = CallTarget::new(&item_fn, args.module.as_ref());
+        let accumulator = if args.accumulate {
+            Some(Accumulator::new(item_fn.sig.nameable_return_type()))
+        } else {
+            None
+        };
+
+        let buffer = if args.accumulate || args.stack || args.cps {
+            None
+        } else {
+            Buffer::new(item_fn.sig.nameable_return_type())
+        };
+
+        let holes = if args.accumulate || args.stack || args.cps || buffer.is_some() {
+            None
+        } else {
+            item_fn.sig.nameable_return_type().map(Holes::new)
+        };
 
-        let expr = match expr {
-            Some(expr) => self.fold_expr(*expr),
-            None => verbatim! { Action::Return(()) },
+        let frames = if args.stack {
+            Some(Frames::new())
+        } else {
+            None
+        };
+        let continuations = if args.cps {
+            Some(Continuations::new())
+        } else {
+            None
         };
 
-        ExprReturn {
-            expr: Some(Box::new(expr)),
-            ..expr_return
+        RecursionTransformer {
+            item_fn,
+            args,
+            acc: Ident::new("acc", span),
+            label: Lifetime {
+                apostrophe: span,
+                ident: Ident::new("recursion", span),
+            },
+            body_label: Lifetime {
+                apostrophe: span,
+                ident: Ident::new("body", span),
+            },
+            accumulator,
+            buffer,
+            holes,
+            tries: vec![],
+            frames,
+            continuations,
+            group: None,
+            results: 0,
+            hidden: vec![],
+            receiver,
+            receiver_kind,
+            target,
+            shadowed: false,
+            nested: false,
+            tail: false,
+            breakables: vec![],
+            warnings: vec![],
+            errors: vec![],
         }
     }
 
-    fn fold_expr(&mut self, expr: Expr) -> Expr {
-        match expr {
-            Expr::Call(expr_call) => {
-                let func = &*expr_call.func;
-                let func_ident: Ident = parse_quote!(#func);
+    /// Turns the parameters into plain bindings in the signature, returning it
+    /// with the patterns rebinding them in the loop and the expressions making
+    /// up the first iteration's state.
+    fn loop_inputs(&self, sig: Signature) -> (Signature, Vec<Pat>, Vec<Expr>) {
+        let (mut input_pats, _) = sig.split_inputs();
+        let mut input_exprs: Vec<Expr> = vec![];
 
-                if func_ident != self.item_fn.sig.ident {
-                    verbatim! { Action::Return(#expr_call) }
-                } else {
-                    let args = &expr_call.args;
-                    verbatim! { Action::Continue((#args)) }
+        // Parameters are rebound inside the loop, which is where they need to be
+        // mutable and where any other pattern is destructured. In the signature
+        // they become plain bindings, with fresh names where they had none.
+        let mut sig = sig;
+        let receiver_arg = sig.receiver().cloned();
+        let params = sig
+            .inputs
+            .iter_mut()
+            .filter(|arg| Some(&**arg) != receiver_arg.as_ref());
+        for (index, (param, pat)) in params.zip(&input_pats).enumerate() {
+            if let Some((_, value)) = self.hidden.iter().find(|(hidden, _)| *hidden == index) {
+                input_exprs.push(value.clone());
+                continue;
+            }
+            if let FnArg::Typed(PatType { pat: param_pat, .. }) = param {
+                let ident = match pat {
+                    Pat::Ident(PatIdent {
+                        by_ref: None,
+                        subpat: None,
+                        ident,
+                        ..
+                    }) => ident.clone(),
+                    _ => Ident::new(&format!("arg{}", index), Span::mixed_site()),
+                };
+                input_exprs.push(parse_quote!(#ident));
+                *param_pat = parse_quote!(#ident);
+            }
+        }
+
+        // Hidden parameters start out with their initial value instead, and the
+        // signature only keeps the others.
+        let mut index = 0;
+        let hidden = &self.hidden;
+        sig.inputs = mem::take(&mut sig.inputs)
+            .into_pairs()
+            .filter(|pair| {
+                if Some(pair.value()) == receiver_arg.as_ref() {
+                    return true;
                 }
-            },
-            Expr::MethodCall(expr_method_call) => {
-                let func_ident = &expr_method_call.method;
+                index += 1;
+                hidden.iter().all(|(hidden, _)| *hidden != index - 1)
+            })
+            .collect();
+
+        // The receiver comes last, so arguments are evaluated before it is moved
+        // into the next iteration.
+        if let (Some(mut receiver_pat), Some(receiver)) = (sig.receiver_pat(), &self.receiver) {
+            input_exprs.push(parse_quote!(self));
+            receiver_pat.ident = receiver.clone();
+            input_pats.push(Pat::Ident(receiver_pat));
+        }
+
+        sig.inputs.iter_mut().for_each(|arg| match arg {
+            FnArg::Receiver(receiver) if receiver.reference.is_none() => {
+                receiver.mutability = None;
+            }
+            FnArg::Typed(PatType { pat, .. }) => {
+                if let Pat::Ident(pat_ident) = &mut **pat {
+                    pat_ident.mutability = None;
+                }
+            }
+            _ => {}
+        });
+
+        (sig, input_pats, input_exprs)
+    }
+
+    fn transform_item_fn(&mut self) -> Result<ItemFn> {
+        let item_fn = self.transform_body()?;
+        Ok(self.fold_item_fn(item_fn))
+    }
+
+    /// Transforms the recursive calls of the body, leaving the signature as it
+    /// is.
+    fn transform_body(&mut self) -> Result<ItemFn> {
+        let mut item_fn = self.item_fn.clone();
+        self.hidden = self.hidden_params()?;
 
-                if *func_ident != self.item_fn.sig.ident {
-                    verbatim! { Action::Return(#expr_method_call) }
+        // rename the receiver, which is rebound on every iteration
+        if let Some(ident) = &self.receiver {
+            let mut renamer = ReceiverRenamer {
+                ident: ident.clone(),
+            };
+            renamer.visit_block_mut(&mut item_fn.block);
+        }
+
+        // `tail!(f(x))` is `f(x)`, which must be eliminated as a tail call
+        self.mark_tail_calls(&mut item_fn.block);
+
+        // a parameter may shadow the function in the whole body
+        let (input_pats, _) = item_fn.sig.split_inputs();
+        let shadows = input_pats.iter().any(|pat| self.binds(pat));
+
+        if self.frames.is_some() || self.continuations.is_some() {
+            if let Some(asyncness) = &item_fn.sig.asyncness {
+                let option = if self.frames.is_some() {
+                    "stack"
                 } else {
-                    let args = &expr_method_call.args;
-                    verbatim! { Action::Continue((#args)) }
+                    "cps"
+                };
+                let message = format!("`{}` does not support async functions", option);
+                return Err(Error::new(asyncness.span(), message));
+            }
+        }
+
+        // holes are only needed if constructors wrap recursive calls
+        if self.holes.is_some() && !constructor::wraps_call(self, &item_fn.block) {
+            self.holes = None;
+        }
+
+        // the variables the code after a call may use, with frames or continuations
+        let mut scope: Vec<_> = input_pats.iter().flat_map(scope::bindings).collect();
+        scope.extend(self.receiver.clone());
+
+        if self.frames.is_some() {
+            // split the body at its recursive calls, into code running in frames
+            let body = self.scoped(shadows, |this| this.split_body(&item_fn.block, scope));
+            item_fn.block.stmts = vec![Stmt::Expr(Expr::Verbatim(body))];
+        } else if self.continuations.is_some() {
+            // rewrite the body into steps, passing the rest of it to recursive calls
+            let body = self.scoped(shadows, |this| this.cps_body(&item_fn.block, scope));
+            item_fn.block.stmts = vec![Stmt::Expr(Expr::Verbatim(body))];
+        } else {
+            // transform tail calls, starting from the last expression
+            self.scoped(shadows, |this| this.visit_stmts(&mut item_fn.block, true));
+        }
+
+        // only once the body is visited is it known whether the buffer is used
+        if let Err(error) = validate_const(&item_fn.sig, self.const_mode()) {
+            self.errors.push(error);
+        }
+
+        let mut errors = mem::take(&mut self.errors);
+        if let Some(Accumulator {
+            operator: Some(_), ..
+        }) = self.accumulator
+        {
+            errors.extend(self.tries.iter().map(|span| {
+                let message = "`?` cannot be used in a function accumulating operands, as it \
+                               returns without them";
+                Error::new(*span, message)
+            }));
+        }
+        if self.args.strict {
+            errors.extend(self.warnings.drain(..).map(Warning::into_error));
+        }
+        combine(errors)?;
+
+        Ok(item_fn)
+    }
+
+    /// The declaration and the check at the start of each iteration bounding
+    /// the depth of recursion by `max_depth`, which panic in `name` once there
+    /// are too many calls in progress. With frames or continuations, those are
+    /// the calls waiting on the stack and the current one; otherwise each
+    /// iteration is a call nested in the previous one.
+    fn depth_bound(&self, name: &Ident) -> (Option<TokenStream2>, Option<TokenStream2>) {
+        let max_depth = match self.args.max_depth {
+            Some(max_depth) => max_depth,
+            None => return (None, None),
+        };
+        let depth = Ident::new("depth", Span::mixed_site());
+        let pending = match (&self.frames, &self.continuations) {
+            (Some(frames), _) => Some(frames.depth()),
+            (_, Some(continuations)) => Some(continuations.depth()),
+            _ => None,
+        };
+
+        let message = format!(
+            "`{}` recursed deeper than its `max_depth` of {}",
+            name, max_depth
+        );
+        let (depth_init, count) = match pending {
+            Some(pending) => (None, quote!(let #depth: u64 = #pending;)),
+            None => (Some(quote!(let mut #depth: u64 = 0;)), quote!(#depth += 1;)),
+        };
+        let depth_check = quote! {
+            #count
+            if #depth > #max_depth {
+                panic!(#message);
+            }
+        };
+        (depth_init, Some(depth_check))
+    }
+
+    /// How the function is transformed, if in a way that keeps what is pending
+    /// around the calls on the heap or in traits, which a `const fn` cannot.
+    fn const_mode(&self) -> Option<&'static str> {
+        if self.accumulator.is_some() {
+            Some("with `accumulate`")
+        } else if self.frames.is_some() {
+            Some("with `stack`")
+        } else if self.continuations.is_some() {
+            Some("with `cps`")
+        } else if matches!(&self.buffer, Some(buffer) if buffer.used) {
+            Some("returning a collection, whose pieces are gathered in a buffer")
+        } else if self.holes.is_some() {
+            Some("wrapping its recursive calls in constructors, whose holes are filled later")
+        } else {
+            None
+        }
+    }
+
+    /// Finds the parameters named in `acc(..)`, with their initial value.
+    ///
+    /// The value is bound with the type of the parameter, so that a literal
+    /// such as `0` has the type of the parameter rather than a default one.
+    fn hidden_params(&self) -> Result<Vec<(usize, Expr)>> {
+        let (input_pats, input_types) = self.item_fn.sig.split_inputs();
+        let mut hidden = vec![];
+        let mut errors = vec![];
+
+        for HiddenParam { name, value } in &self.args.acc {
+            let index = input_pats.iter().position(|pat| match pat {
+                Pat::Ident(PatIdent {
+                    by_ref: None,
+                    subpat: None,
+                    ident,
+                    ..
+                }) => ident == name,
+                _ => false,
+            });
+            let index = match index {
+                Some(index) if hidden.iter().all(|(hidden, _)| *hidden != index) => index,
+                Some(_) => {
+                    let message = format!("`{}` is hidden more than once", name);
+                    errors.push(Error::new(name.span(), message));
+                    continue;
                 }
-            },
-            Expr::Verbatim(_) => expr,
-            _ => verbatim! { Action::Return(#expr) }
+                None => {
+                    let message = format!("`{}` is not a parameter of the function", name);
+                    errors.push(Error::new(name.span(), message));
+                    continue;
+                }
+            };
+
+            let span = value.as_ref().map_or(name.span(), |value| value.span());
+            let value = match value {
+                Some(value) => quote!(#value),
+                None => quote_spanned!(span=> Default::default()),
+            };
+            let ty = &input_types[index];
+            let value = if utils::is_nameable(ty) {
+                verbatim! {{
+                    let #name: #ty = #value;
+                    #name
+                }}
+            } else {
+                verbatim!(#value)
+            };
+            hidden.push((index, value));
         }
+        hidden.sort_by_key(|(index, _)| *index);
+
+        combine(errors)?;
+        Ok(hidden)
     }
-}
 
-impl RecursionTransformer {
-    fn new(item_fn: ItemFn) -> Self {
-        RecursionTransformer { item_fn }
+    /// Whether returning from the body leaves the labelled block holding it,
+    /// so that the result goes through what is pending around the calls.
+    fn leaves_body(&self) -> bool {
+        self.accumulator.is_some() || self.buffer.is_some() || self.holes.is_some()
     }
 
-    fn transform_item_fn(&mut self) -> ItemFn {
-        let mut item_fn = self.item_fn.clone();
+    /// Runs `f` in a nested scope, in which the function's name is shadowed if
+    /// it already was or if the scope `shadows` it.
+    fn scoped<T>(&mut self, shadows: bool, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = self.shadowed;
+        self.shadowed |= shadows;
+        let result = f(self);
+        self.shadowed = outer;
+        result
+    }
+
+    /// Runs `f` inside a closure or an async block, which neither `return` nor
+    /// `break` can leave.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = mem::replace(&mut self.nested, true);
+        let breakables = mem::take(&mut self.breakables);
+        let result = f(self);
+        self.nested = outer;
+        self.breakables = breakables;
+        result
+    }
+
+    /// Runs `f` inside a loop or a labelled block, whose value is the value of
+    /// the `break` expressions targeting it.
+    fn breakable<T>(
+        &mut self,
+        label: Option<Lifetime>,
+        is_loop: bool,
+        tail: bool,
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        self.breakables.push(Breakable {
+            label,
+            is_loop,
+            tail,
+        });
+        let result = f(self);
+        self.breakables.pop();
+        result
+    }
+
+    /// Whether the value of a `break` is in tail position, which is the case if
+    /// the loop or block it breaks out of is.
+    fn breaks_tail(&self, label: Option<&Lifetime>) -> bool {
+        let mut breakables = self.breakables.iter().rev();
+        let target = match label {
+            Some(label) => breakables.find(|breakable| breakable.label.as_ref() == Some(label)),
+            None => breakables.find(|breakable| breakable.is_loop),
+        };
+
+        matches!(target, Some(Breakable { tail: true, .. }))
+    }
 
-        // transform `return` expression
-        self.visit_item_fn_mut(&mut item_fn);
+    /// The names of the functions whose calls are eliminated, which are those of
+    /// the whole group in a `recursive_group!`.
+    fn names(&self) -> Vec<&Ident> {
+        match &self.group {
+            Some(group) => group.names(),
+            None => vec![&self.item_fn.sig.ident],
+        }
+    }
+
+    /// Whether `pat` shadows the function, or any function of its group.
+    fn binds(&self, pat: &Pat) -> bool {
+        self.names().iter().any(|name| scope::binds(pat, name))
+    }
+
+    /// Whether `stmts` declare an item or a binding shadowing the function, or
+    /// any function of its group.
+    fn declares(&self, stmts: &[Stmt]) -> bool {
+        self.names().iter().any(|name| scope::declares(stmts, name))
+    }
+
+    /// Whether the `let` in the condition of an `if` or `while` binds the
+    /// function's name for the body.
+    fn cond_binds(&self, cond: &Expr) -> bool {
+        match cond {
+            Expr::Let(expr_let) => self.binds(&expr_let.pat),
+            _ => false,
+        }
+    }
+
+    /// Visits an expression, which is in tail position if `tail` is.
+    fn visit_tail(&mut self, expr: &mut Expr, tail: bool) {
+        self.tail = tail;
+        self.visit_expr_mut(expr);
+    }
+
+    /// Visits a macro invocation in tail position.
+    ///
+    /// Macros opted into with `macros(..)` expand the arguments given there in
+    /// tail position, so recursive calls in them are eliminated, and warned
+    /// about in the others. Standard library macros hold no tail calls, so recursive calls
+    /// in them are warned about as anywhere else. Any other macro mentioning
+    /// the function is opaque, and recursive calls in it are left as they are,
+    /// with a warning.
+    fn visit_tail_macro(&mut self, mac: &mut Macro) {
+        let name = match mac.path.segments.last() {
+            Some(segment) => segment.ident.clone(),
+            None => return,
+        };
 
-        // transform last expression
-        let mut last_stmt = item_fn.block.stmts.last().unwrap().clone();
-        self.visit_stmt_mut(&mut last_stmt);
-        let fn_body_last_stmt = item_fn.block.stmts.last_mut().unwrap();
-        *fn_body_last_stmt = last_stmt;
+        let tails = self
+            .args
+            .macros
+            .iter()
+            .find(|tail_macro| tail_macro.name == name)
+            .map(|tail_macro| tail_macro.tails.clone());
+        if let Some(tails) = tails {
+            let parser = punctuated::Punctuated::<Expr, Token![,]>::parse_terminated;
+            let mut args = match mac.parse_body_with(parser) {
+                Ok(args) => args,
+                Err(_) => {
+                    self.warnings.push(Warning {
+                        span: mac.path.span(),
+                        message: format!(
+                            "the arguments of `{}!` are not expressions, so recursive calls in \
+                             them are not eliminated",
+                            name
+                        ),
+                    });
+                    return;
+                }
+            };
 
-        self.fold_item_fn(item_fn)
+            for (index, arg) in args.iter_mut().enumerate() {
+                self.visit_tail(arg, tails.contains(&index));
+            }
+            mac.tokens = args.to_token_stream();
+        } else if STD_MACROS.iter().any(|std_macro| name == std_macro) {
+            self.warn_macro(mac);
+        } else if self.mentioned_in(mac.tokens.clone()) {
+            self.warnings.push(Warning {
+                span: mac.path.span(),
+                message: format!(
+                    "recursive calls in `{0}!` are not eliminated; if it expands some of its \
+                     arguments in tail position, give their indices, as in \
+                     `#[recursive(macros({0}(1, 2)))]`",
+                    name
+                ),
+            });
+        }
     }
 
-    fn transform_expr_return(&self, node: &mut ExprReturn) {
-        if let Some(ref mut some_expr) = node.expr {
-            self.transform_expr(some_expr);
+    /// Whether `tokens`, such as the body of a macro invocation, mention the
+    /// function or any function of its group.
+    fn mentioned_in(&self, tokens: TokenStream2) -> bool {
+        self.names()
+            .iter()
+            .any(|name| target::mentions(tokens.clone(), &name.to_string()))
+    }
+
+    /// Warns about the recursive calls in a macro invocation whose arguments
+    /// are not in tail position, which are left as they are.
+    fn warn_macro(&mut self, mac: &Macro) {
+        if self.shadowed || !self.mentioned_in(mac.tokens.clone()) {
+            return;
+        }
+        let name = match mac.path.segments.last() {
+            Some(segment) => &segment.ident,
+            None => return,
+        };
+        self.warnings.push(Warning {
+            span: mac.path.span(),
+            message: format!(
+                "recursive calls in `{}!` are not in tail position, so they are left as they \
+                 are and still grow the stack",
+                name
+            ),
+        });
+    }
+
+    /// Visits the statements of a block in the scope of its items and `let`
+    /// bindings. The last expression is in tail position if `tail` is.
+    fn visit_stmts(&mut self, block: &mut Block, tail: bool) {
+        if tail {
+            self.build_stmts(&mut block.stmts);
+        }
+
+        let shadows = self.declares(&block.stmts);
+        let len = block.stmts.len();
+
+        self.scoped(shadows, |this| {
+            for (index, stmt) in block.stmts.iter_mut().enumerate() {
+                let is_last = index + 1 == len;
+
+                match stmt {
+                    Stmt::Expr(expr) => this.visit_tail(expr, tail && is_last),
+                    // `name! { .. }` is an item, unless it is the block's value.
+                    Stmt::Item(Item::Macro(ItemMacro {
+                        ident: None,
+                        mac,
+                        semi_token: None,
+                        ..
+                    })) if tail && is_last => this.visit_tail_macro(mac),
+                    Stmt::Item(Item::Macro(ItemMacro {
+                        ident: None, mac, ..
+                    })) => this.warn_macro(mac),
+                    Stmt::Semi(Expr::Verbatim(tokens), _) => {
+                        if let Ok(mut let_else) = parse2::<LetElse>(tokens.clone()) {
+                            this.visit_expr_mut(&mut let_else.init);
+                            this.visit_block_mut(&mut let_else.diverge);
+                            *tokens = let_else.to_token_stream();
+
+                            this.shadowed |= this.binds(&let_else.pat);
+                        }
+                    }
+                    _ => this.visit_stmt_mut(stmt),
+                }
+
+                // A `let` binding shadows the function after its initializer.
+                if let Stmt::Local(local) = stmt {
+                    this.shadowed |= this.binds(&local.pat);
+                }
+            }
+        });
+    }
+
+    fn is_receiver(&self, expr: &Expr) -> bool {
+        match (expr, &self.receiver) {
+            (Expr::Path(expr_path), Some(receiver)) => expr_path.path.is_ident(receiver),
+            _ => false,
+        }
+    }
+
+    /// The next iteration's receiver of a recursive method call, which may be
+    /// called on something other than `self`.
+    fn next_receiver(&self, expr: &Expr) -> Option<Expr> {
+        let receiver_kind = self.receiver_kind.as_ref()?;
+
+        if self.is_receiver(expr) {
+            Some(expr.clone())
         } else {
-            node.expr = verbatim!(some, Action::Return(()));
+            Some(receiver_kind.next_receiver(expr))
         }
     }
 
-    fn transform_expr(&self, expr: &mut Expr) {
-        let fn_name = &self.item_fn.sig.ident;
+    /// The loop state of the next iteration for a call to the annotated
+    /// function, or `None` for any other expression.
+    ///
+    /// In a call through a path, such as `f(..)` or `Self::f(self, ..)`, the
+    /// first argument is the receiver if the function is a method. A call
+    /// leaving out the hidden parameters, as callers do, starts them out with
+    /// their initial value again.
+    fn next_state(&self, expr: &Expr) -> Option<TokenStream2> {
+        if let Some(group) = &self.group {
+            return group.next_state(expr, self.shadowed);
+        }
+
+        let (mut args, receiver) = match expr {
+            Expr::Call(expr_call) if self.target.resolve(&expr_call.func, self.shadowed) => {
+                let mut args = expr_call.args.clone().into_iter();
+                let receiver = match &self.receiver_kind {
+                    Some(receiver_kind) => args.next().map(|arg| {
+                        if self.is_receiver(&arg) {
+                            arg
+                        } else {
+                            receiver_kind.receiver_arg(&arg)
+                        }
+                    }),
+                    None => None,
+                };
+                (args.collect::<Vec<_>>(), receiver)
+            }
+            Expr::MethodCall(expr_method_call)
+                if self.is_recursive_method_call(expr_method_call) =>
+            {
+                let args = expr_method_call.args.clone().into_iter().collect();
+                let receiver = self.next_receiver(&expr_method_call.receiver)?;
+                (args, Some(receiver))
+            }
+            _ => return None,
+        };
+
+        let (_, input_types) = self.item_fn.sig.split_inputs();
+        if args.len() + self.hidden.len() == input_types.len() {
+            for (index, value) in &self.hidden {
+                args.insert(*index, value.clone());
+            }
+        }
 
+        // The arguments are bound with the types of the parameters, so that they
+        // are coerced as in a call, e.g. `&Box<T>` to `&T`.
+        let state = Ident::new("state", Span::mixed_site());
+        let types = input_types.iter().map(|ty| {
+            if utils::is_nameable(ty) {
+                quote!(#ty)
+            } else {
+                quote!(_)
+            }
+        });
+        let receiver_type = receiver.iter().map(|_| quote!(_));
+        let types = if args.len() == input_types.len() {
+            quote!(: (#(#types,)* #(#receiver_type,)*))
+        } else {
+            TokenStream2::new()
+        };
+        let receiver = receiver.iter();
+
+        Some(quote!({
+            let #state #types = (#(#args,)* #(#receiver,)*);
+            #state
+        }))
+    }
+
+    /// Rewrites a call to the annotated function into an assignment of the next
+    /// iteration's arguments.
+    fn transform_call(&self, expr: &mut Expr) {
+        if let Some(state) = self.next_state(expr) {
+            let acc = &self.acc;
+            let label = &self.label;
+            *expr = verbatim! {{
+                #acc = #state;
+                continue #label;
+            }};
+        }
+    }
+
+    fn is_recursive_method_call(&self, expr_method_call: &ExprMethodCall) -> bool {
+        let arguments = match &expr_method_call.turbofish {
+            Some(MethodTurbofish { args, .. }) => {
+                PathArguments::AngleBracketed(parse_quote!(<#args>))
+            }
+            None => PathArguments::None,
+        };
+
+        self.receiver.is_some()
+            && expr_method_call.method == self.item_fn.sig.ident
+            && self.item_fn.sig.is_own_instantiation(&arguments)
+    }
+
+    /// Whether the callee of a call expression is the annotated function, or
+    /// any function of its group.
+    fn resolve(&self, func: &Expr) -> bool {
+        match &self.group {
+            Some(group) => group.resolve(func, self.shadowed).is_some(),
+            None => self.target.resolve(func, self.shadowed),
+        }
+    }
+
+    /// Whether an expression is a call to the annotated function, or to any
+    /// function of its group.
+    fn is_recursive_call(&self, expr: &Expr) -> bool {
         match expr {
-            Expr::Call(expr_call) => {
-                let func = expr_call.func.clone();
-                let func_id: Ident = parse_quote!(#func);
+            Expr::Call(expr_call) => self.resolve(&expr_call.func),
+            Expr::MethodCall(expr_method_call) => self.is_recursive_method_call(expr_method_call),
+            _ => false,
+        }
+    }
 
-                if func_id != *fn_name {
-                    *expr = verbatim! { Action::Return(#expr_call) };
-                } else {
-                    let args = expr_call.args.clone();
-                    *expr = verbatim! { Action::Continue((#args)) };
+    /// Whether an expression is a recursive call whose value can be
+    /// accumulated, or an operation on one.
+    fn accumulates(&self, expr: &Expr) -> bool {
+        if self.item_fn.sig.asyncness.is_some() || self.is_recursive_call(expr) {
+            return self.item_fn.sig.asyncness.is_none();
+        }
+
+        match Operation::of(&mut expr.clone()) {
+            Some(operation) => {
+                self.accumulates(operation.left) || self.accumulates(operation.right)
+            }
+            None => false,
+        }
+    }
+
+    /// Visits an operation in tail position combining a recursive call with an
+    /// operand, such as `n + sum(n - 1)`, returning whether it is one.
+    ///
+    /// The operand is folded into an accumulator, which leaves the call in tail
+    /// position. Operations on the result of the call, as in
+    /// `sum(n - 1) + n`, are accumulated on the other side.
+    fn visit_accumulated(&mut self, expr: &mut Expr) -> bool {
+        if self.is_recursive_call(expr) {
+            return false;
+        }
+        let operation = match Operation::of(expr) {
+            Some(operation) => operation,
+            None => return false,
+        };
+        let side = if self.accumulates(operation.right) {
+            Side::Right
+        } else if self.accumulates(operation.left) {
+            Side::Left
+        } else {
+            return false;
+        };
+        let (call, operand) = match side {
+            Side::Right => (&mut *operation.right, &mut *operation.left),
+            Side::Left => (&mut *operation.left, &mut *operation.right),
+        };
+
+        let operator = operation.operator;
+        let accumulator = match &mut self.accumulator {
+            Some(accumulator) => accumulator,
+            None => return false,
+        };
+        match accumulator.operator {
+            Some(first) if first != operator => {
+                let message = format!(
+                    "recursive calls are combined with `{}` here but with `{}` before, while \
+                     only one operator can be accumulated",
+                    operator.name(),
+                    first.name()
+                );
+                self.errors.push(Error::new(call.span(), message));
+                return true;
+            }
+            _ => accumulator.operator = Some(operator),
+        }
+        accumulator.concat |= operation.concat;
+
+        self.visit_expr_mut(operand);
+        self.visit_tail(call, true);
+
+        let continue_expr = match call {
+            Expr::Verbatim(continue_expr) => continue_expr.clone(),
+            _ => return true,
+        };
+        let accumulate = match &self.accumulator {
+            Some(accumulator) => accumulator.accumulate(side, &operation),
+            None => return true,
+        };
+        *expr = verbatim! {{
+            #accumulate
+            #continue_expr
+        }};
+
+        true
+    }
+
+    /// Warns about a call to the annotated function that is left as it is,
+    /// because it is not in tail position.
+    fn warn_recursive_call(&mut self, expr: &mut Expr) {
+        if let Some(span) = tail::unmark(expr) {
+            self.report_marked(span, self.is_recursive_call(expr));
+            return;
+        }
+
+        let (span, args) = match expr {
+            Expr::Call(expr_call) if self.resolve(&expr_call.func) => {
+                let receiver = self.receiver_kind.is_some() as usize;
+                (
+                    expr_call.func.span(),
+                    expr_call.args.len().saturating_sub(receiver),
+                )
+            }
+            Expr::MethodCall(expr_method_call)
+                if self.is_recursive_method_call(expr_method_call) =>
+            {
+                (expr_method_call.method.span(), expr_method_call.args.len())
+            }
+            _ => return,
+        };
+
+        // Outside of the loop, the function only takes the public parameters.
+        if !self.hidden.is_empty() && args == self.item_fn.sig.split_inputs().0.len() {
+            let message = "this recursive call passes the parameters hidden with `acc(..)`, \
+                           which only a tail call can do";
+            self.errors.push(Error::new(span, message));
+            return;
+        }
+
+        let message = if self.nested {
+            "this recursive call is made from a closure or an async block, so it is not \
+             turned into an iteration and still grows the stack; only calls whose value is \
+             the function's result can be"
+        } else {
+            "this recursive call is not a tail call, as its value is used by the enclosing \
+             expression, so it still grows the stack; carry the pending work in an \
+             accumulator argument and return the call's value as it is"
+        };
+
+        self.warnings.push(Warning {
+            span,
+            message: message.to_string(),
+        });
+    }
+}
+
+/// The visitor walks the whole body, keeping track of whether the current
+/// expression is in tail position, i.e. whether its value is the function's
+/// result.
+impl VisitMut for RecursionTransformer {
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        let tail = mem::replace(&mut self.tail, false);
+        let is_async = self.item_fn.sig.asyncness.is_some();
+
+        if tail
+            && (self.visit_accumulated(node)
+                || self.visit_built(node)
+                || self.visit_constructed(node))
+        {
+            return;
+        }
+
+        match node {
+            Expr::Block(expr_block) => {
+                let label = expr_block.label.clone().map(|label| label.name);
+                match label {
+                    Some(_) => self.breakable(label, false, tail, |this| {
+                        this.visit_stmts(&mut expr_block.block, tail)
+                    }),
+                    None => self.visit_stmts(&mut expr_block.block, tail),
                 }
             }
-            Expr::MethodCall(expr_method_call) => {
-                let func_id = expr_method_call.method.clone();
-                if func_id != *fn_name {
-                    *expr = verbatim! { Action::Return(#expr_method_call) };
-                } else {
-                    let args = expr_method_call.args.clone();
-                    *expr = verbatim! { Action::Continue((#args)) };
+            Expr::Unsafe(expr_unsafe) => self.visit_stmts(&mut expr_unsafe.block, tail),
+            Expr::Paren(expr_paren) => self.visit_tail(&mut expr_paren.expr, tail),
+            Expr::Group(expr_group) => self.visit_tail(&mut expr_group.expr, tail),
+            Expr::If(expr_if) => {
+                self.visit_expr_mut(&mut expr_if.cond);
+
+                let shadows = self.cond_binds(&expr_if.cond);
+                self.scoped(shadows, |this| {
+                    this.visit_stmts(&mut expr_if.then_branch, tail)
+                });
+
+                if let Some((_, else_branch)) = &mut expr_if.else_branch {
+                    self.visit_tail(else_branch, tail);
                 }
             }
-            Expr::Match(expr) => expr.arms.iter_mut().for_each(|arm| {
-                self.transform_expr(&mut arm.body);
-            }),
-            Expr::If(expr) => {
-                if let Some(last_stmt) = expr.then_branch.stmts.last_mut() {
-                    if let Stmt::Expr(expr) = last_stmt {
-                        self.transform_expr(expr);
-                    }
+            Expr::Match(expr_match) => {
+                self.visit_expr_mut(&mut expr_match.expr);
+
+                for arm in &mut expr_match.arms {
+                    let shadows = self.binds(&arm.pat);
+                    self.scoped(shadows, |this| {
+                        if let Some((_, guard)) = &mut arm.guard {
+                            this.visit_expr_mut(guard);
+                        }
+                        this.visit_tail(&mut arm.body, tail);
+                    });
                 }
-                if let Some((_, ref mut expr)) = &mut expr.else_branch {
-                    self.transform_expr(expr);
+            }
+            Expr::Loop(expr_loop) => {
+                let label = expr_loop.label.clone().map(|label| label.name);
+                self.breakable(label, true, tail, |this| {
+                    this.visit_block_mut(&mut expr_loop.body)
+                });
+            }
+            Expr::While(expr_while) => {
+                self.visit_expr_mut(&mut expr_while.cond);
+
+                let label = expr_while.label.clone().map(|label| label.name);
+                let shadows = self.cond_binds(&expr_while.cond);
+                self.breakable(label, true, false, |this| {
+                    this.scoped(shadows, |this| this.visit_block_mut(&mut expr_while.body))
+                });
+            }
+            Expr::ForLoop(expr_for_loop) => {
+                self.visit_expr_mut(&mut expr_for_loop.expr);
+
+                let label = expr_for_loop.label.clone().map(|label| label.name);
+                let shadows = self.binds(&expr_for_loop.pat);
+                self.breakable(label, true, false, |this| {
+                    this.scoped(shadows, |this| {
+                        this.visit_block_mut(&mut expr_for_loop.body)
+                    })
+                });
+            }
+            Expr::Macro(expr_macro) if tail => self.visit_tail_macro(&mut expr_macro.mac),
+            Expr::Macro(expr_macro) => self.warn_macro(&expr_macro.mac),
+            Expr::Break(ExprBreak {
+                label,
+                expr: Some(expr),
+                ..
+            }) => {
+                let tail = self.breaks_tail(label.as_ref());
+                self.visit_tail(expr, tail);
+
+                // Breaking with a tail call would be unreachable.
+                if let Expr::Verbatim(_) = **expr {
+                    *node = *expr.clone();
+                }
+            }
+            Expr::Return(ExprReturn {
+                expr: Some(expr), ..
+            }) => {
+                let tail = !self.nested;
+                self.visit_tail(expr, tail);
+
+                // `return` of a tail call would be unreachable.
+                match (tail, &**expr) {
+                    (true, Expr::Verbatim(_)) => *node = *expr.clone(),
+                    (true, _) if self.leaves_body() => {
+                        let body_label = &self.body_label;
+                        *node = verbatim!(break #body_label #expr);
+                    }
+                    _ => {}
                 }
             }
-            Expr::Block(expr) => {
-                if let Some(last_stmt) = expr.block.stmts.last_mut() {
-                    if let Stmt::Expr(expr) = last_stmt {
-                        self.transform_expr(expr);
+            // The right operand of a lazy boolean operator is only evaluated to
+            // be the result, so `a && f(x)` is `if a { f(x) } else { false }`.
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::And(_) | BinOp::Or(_),
+                right,
+                ..
+            }) if tail && self.accumulator.is_some() => {
+                self.visit_expr_mut(left);
+                self.visit_tail(right, true);
+            }
+            Expr::Try(expr_try) if !self.nested => {
+                self.tries.push(expr_try.question_token.span());
+                visit_mut::visit_expr_try_mut(self, expr_try);
+            }
+            // `?` converts the error of a recursive call with `From` into the very
+            // same type, so `Ok(f(x)?)` is just `f(x)`.
+            Expr::Call(expr_call) if tail && is_rewrapped_try(expr_call) => {
+                if let Some(Expr::Try(expr_try)) = expr_call.args.first_mut() {
+                    self.visit_tail(&mut expr_try.expr, true);
+
+                    if let Expr::Verbatim(_) = *expr_try.expr {
+                        *node = *expr_try.expr.clone();
                     }
                 }
             }
-            Expr::Return(_) | Expr::Verbatim(_) => {
-                // Ignore return expressions as they are handled separately.
-                // And, ignore verbatim!{} expressions generated by this macro.
+            // A call of an async function is a future, and only once awaited is
+            // it the function's result.
+            Expr::Await(expr_await) if tail && is_async => {
+                let base = &mut *expr_await.base;
+                visit_mut::visit_expr_mut(self, base);
+                self.transform_call(base);
+
+                match base {
+                    Expr::Verbatim(_) => *node = base.clone(),
+                    _ => self.warn_recursive_call(base),
+                }
             }
-            _ => {
-                *expr = verbatim! { Action::Return(#expr) };
+            _ => visit_mut::visit_expr_mut(self, node),
+        }
+
+        if tail && !is_async {
+            self.transform_call(node);
+        } else if !tail {
+            if let Some(span) = tail::unmark(node) {
+                self.report_misplaced(span);
             }
         }
+        self.warn_recursive_call(node);
     }
-}
 
-impl VisitMut for RecursionTransformer {
-    fn visit_expr_return_mut(&mut self, node: &mut ExprReturn) {
-        self.transform_expr_return(node);
+    fn visit_item_mut(&mut self, _node: &mut Item) {
+        // Nested items are functions of their own.
     }
 
-    fn visit_expr_mut(&mut self, node: &mut Expr) {
-        self.transform_expr(node);
+    fn visit_expr_async_mut(&mut self, node: &mut ExprAsync) {
+        self.nested(|this| visit_mut::visit_expr_async_mut(this, node));
+    }
+
+    fn visit_expr_closure_mut(&mut self, node: &mut ExprClosure) {
+        let shadows = node.inputs.iter().any(|pat| self.binds(pat));
+        self.nested(|this| {
+            this.scoped(shadows, |this| {
+                visit_mut::visit_expr_closure_mut(this, node)
+            })
+        });
+    }
+
+    fn visit_block_mut(&mut self, node: &mut Block) {
+        self.visit_stmts(node, false);
     }
 }
+
+/// Macros of the standard library whose arguments are never in tail position.
+/// `dbg!` evaluates to its argument, but only after printing the value, so a
+/// call in it still returns to `dbg!` rather than being the function's result.
+const STD_MACROS: &[&str] = &[
+    "assert",
+    "assert_eq",
+    "assert_ne",
+    "cfg",
+    "column",
+    "compile_error",
+    "concat",
+    "dbg",
+    "debug_assert",
+    "debug_assert_eq",
+    "debug_assert_ne",
+    "env",
+    "eprint",
+    "eprintln",
+    "file",
+    "format",
+    "format_args",
+    "include",
+    "include_bytes",
+    "include_str",
+    "line",
+    "matches",
+    "module_path",
+    "option_env",
+    "panic",
+    "print",
+    "println",
+    "stringify",
+    "todo",
+    "unimplemented",
+    "unreachable",
+    "vec",
+    "write",
+    "writeln",
+];
+
+/// Whether a call is `Ok(expr?)` or `Some(expr?)`.
+fn is_rewrapped_try(expr_call: &ExprCall) -> bool {
+    let path = match &*expr_call.func {
+        Expr::Path(ExprPath {
+            qself: None, path, ..
+        }) => path,
+        _ => return false,
+    };
+    let variant = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string());
+    let is_wrapper = match variant.collect::<Vec<_>>().as_slice() {
+        [variant] => variant == "Ok" || variant == "Some",
+        [ty, variant] => {
+            (ty == "Result" && variant == "Ok") || (ty == "Option" && variant == "Some")
+        }
+        _ => false,
+    };
+
+    is_wrapper && matches!(expr_call.args.first(), Some(Expr::Try(_))) && expr_call.args.len() == 1
+}
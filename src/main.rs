@@ -1,6 +1,7 @@
-use recursive::recursive;
+use recursive::{recursive, recursive_group};
+use std::rc::Rc;
 
-#[recursive]
+#[recursive(acc(a = 0))]
 fn sum(n: u64, a: u64) -> u64 {
     match n {
         0 => a,
@@ -8,7 +9,7 @@ fn sum(n: u64, a: u64) -> u64 {
     }
 }
 
-#[recursive]
+#[recursive(acc(a = 1))]
 fn factorial(n: u64, a: u64) -> u64 {
     if n == 0 {
         a
@@ -17,7 +18,7 @@ fn factorial(n: u64, a: u64) -> u64 {
     }
 }
 
-#[recursive]
+#[recursive(acc(a))]
 fn repeat(input: &str, n: usize, a: String) -> String {
     if n == 0 {
         a
@@ -26,6 +27,243 @@ fn repeat(input: &str, n: usize, a: String) -> String {
     }
 }
 
+#[recursive(acc(a = 0))]
+fn count<T: PartialEq>(xs: &[T], x: &T, a: usize) -> usize {
+    match xs.split_first() {
+        Some((first, rest)) if first == x => count::<T>(rest, x, a + 1),
+        Some((_, rest)) => count::<T>(rest, x, a),
+        None => a,
+    }
+}
+
+#[recursive]
+fn last<'a, T>(xs: &'a [T], a: Option<&'a T>) -> Option<&'a T>
+where
+    T: Copy,
+{
+    match xs.split_first() {
+        Some((first, rest)) => last(rest, Some(first)),
+        None => a,
+    }
+}
+
+#[recursive]
+fn search(xs: &[u64], (lo, hi): (usize, usize), x: u64) -> Option<usize> {
+    let mid = lo + (hi - lo) / 2;
+    match xs.get(mid) {
+        _ if lo >= hi => None,
+        Some(&value) if value < x => search(xs, (mid + 1, hi), x),
+        Some(&value) if value > x => search(xs, (lo, mid), x),
+        _ => Some(mid),
+    }
+}
+
+#[recursive]
+fn collatz(mut n: u64, steps: u64) -> u64 {
+    if n == 1 {
+        return steps;
+    }
+    n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+    collatz(n, steps + 1)
+}
+
+#[recursive]
+fn strip<'a>(s: &'a str, prefix: &str) -> &'a str {
+    match s.strip_prefix(prefix) {
+        Some(rest) if !prefix.is_empty() => strip(rest, prefix),
+        _ => s,
+    }
+}
+
+#[recursive]
+fn nth<I: Iterator>(mut iter: I, n: usize) -> Option<<I as Iterator>::Item> {
+    match n {
+        0 => iter.next(),
+        _ => {
+            iter.next();
+            nth(iter, n - 1)
+        }
+    }
+}
+
+#[recursive]
+fn apply(f: impl Fn(u64) -> u64, x: u64, n: u64) -> impl std::fmt::Display {
+    match n {
+        0 => x,
+        _ => {
+            let x = f(x);
+            apply(f, x, n - 1)
+        }
+    }
+}
+
+#[recursive]
+fn total(xs: &[u64], a: u64) -> u64 {
+    let [x, rest @ ..] = xs else { return a };
+    total(rest, a + x)
+}
+
+#[recursive]
+fn parse_sum(tokens: &[&str], a: i64) -> Result<i64, std::num::ParseIntError> {
+    match tokens {
+        [] => Ok(a),
+        [token, rest @ ..] => {
+            let n: i64 = token.parse()?;
+            Ok(parse_sum(rest, a + n)?)
+        }
+    }
+}
+
+macro_rules! either {
+    ($cond:expr, $then:expr, $else:expr) => {
+        if $cond {
+            $then
+        } else {
+            $else
+        }
+    };
+}
+
+#[recursive(macros(either(1, 2)))]
+fn gcd(a: u64, b: u64) -> u64 {
+    either!(b == 0, a, gcd(b, a % b))
+}
+
+#[recursive(accumulate)]
+fn triangle(n: u64) -> u64 {
+    match n {
+        0 => 0,
+        _ => n + triangle(n - 1),
+    }
+}
+
+#[recursive(accumulate)]
+fn digits(n: u64) -> String {
+    if n < 10 {
+        return n.to_string();
+    }
+    digits(n / 10) + "," + &(n % 10).to_string()
+}
+
+#[recursive(accumulate)]
+fn maximum(xs: &[u64]) -> u64 {
+    match xs {
+        [] => 0,
+        [x, rest @ ..] => maximum(rest).max(*x),
+    }
+}
+
+#[recursive(stack)]
+fn fibonacci(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    fibonacci(n - 1) + fibonacci(n - 2)
+}
+
+#[recursive]
+fn squares(xs: &[u64]) -> Vec<u64> {
+    match xs {
+        [] => vec![],
+        [x, rest @ ..] => {
+            let mut v = vec![x * x];
+            v.extend(squares(rest));
+            v
+        }
+    }
+}
+
+#[recursive]
+fn join(xs: &[u64]) -> String {
+    match xs {
+        [] => String::new(),
+        [x] => x.to_string(),
+        [x, rest @ ..] => format!("{}, {}", x, join(rest)),
+    }
+}
+
+#[recursive]
+fn skip_spaces(s: &str) -> &str {
+    match s.strip_prefix(' ') {
+        Some(rest) => tail!(skip_spaces(rest)),
+        None => s,
+    }
+}
+
+recursive_group! {
+    fn is_even(n: u64) -> bool {
+        match n {
+            0 => true,
+            _ => is_odd(n - 1),
+        }
+    }
+
+    fn is_odd(n: u64) -> bool {
+        match n {
+            0 => false,
+            _ => become is_even(n - 1),
+        }
+    }
+}
+
+#[recursive]
+mod words {
+    pub fn between(s: &[u8], count: usize) -> usize {
+        match s {
+            [] => count,
+            [b' ', rest @ ..] => between(rest, count),
+            [_, rest @ ..] => within(rest, count + 1),
+        }
+    }
+
+    fn within(s: &[u8], count: usize) -> usize {
+        match s {
+            [] => count,
+            [b' ', rest @ ..] => between(rest, count),
+            [_, rest @ ..] => within(rest, count),
+        }
+    }
+}
+
+mod steps {
+    use recursive::recursive;
+
+    #[recursive(module(crate::steps))]
+    pub fn down(n: u64, steps: u64) -> u64 {
+        match n {
+            0 => steps,
+            _ => crate::steps::down(n - 1, steps + 1),
+        }
+    }
+}
+
+#[recursive(cps)]
+fn partitions(n: u64, k: u64) -> u64 {
+    if n == 0 {
+        return 1;
+    }
+    let without = if k > 1 { partitions(n, k - 1) } else { 0 };
+    let with = if k <= n { partitions(n - k, k) } else { 0 };
+    without + with
+}
+
+#[recursive(cps)]
+fn compositions(n: u64) -> u64 {
+    if n == 0 {
+        return 1;
+    }
+    (1..=n).map(|first| compositions(n - first)).sum()
+}
+
+#[recursive(cps)]
+fn staircase(n: u64) -> u64 {
+    let mut ways = u64::from(n == 0);
+    for step in 1..=n.min(3) {
+        ways += staircase(n - step);
+    }
+    ways
+}
+
 struct Arith(u64);
 
 impl Arith {
@@ -36,13 +274,207 @@ impl Arith {
             _ => self.sum(n - 1, n + a),
         }
     }
+
+    #[recursive]
+    fn add(&mut self, n: u64) {
+        if n > 0 {
+            self.0 += 1;
+            self.add(n - 1)
+        }
+    }
+
+    #[recursive]
+    fn into_sum(mut self, n: u64) -> u64 {
+        match n {
+            0 => self.0,
+            _ => {
+                self.0 += n;
+                self.into_sum(n - 1)
+            }
+        }
+    }
+
+    #[recursive]
+    fn boxed_sum(self: Box<Self>, n: u64, a: u64) -> u64 {
+        match n {
+            0 => a + self.0,
+            _ => self.boxed_sum(n - 1, n + a),
+        }
+    }
+
+    #[recursive]
+    fn shared_sum(self: Rc<Self>, n: u64, a: u64) -> u64 {
+        match n {
+            0 => a + self.0,
+            _ => self.shared_sum(n - 1, n + a),
+        }
+    }
+
+    #[recursive]
+    fn triangle(n: u64, a: u64) -> Arith {
+        match n {
+            0 => Arith(a),
+            _ => Self::triangle(n - 1, n + a),
+        }
+    }
+}
+
+#[recursive]
+impl Arith {
+    fn even(n: u64) -> bool {
+        match n {
+            0 => true,
+            _ => Self::odd(n - 1),
+        }
+    }
+
+    fn odd(n: u64) -> bool {
+        match n {
+            0 => false,
+            _ => Self::even(n - 1),
+        }
+    }
+}
+
+#[recursive]
+trait Countdown {
+    fn step(&self) -> u64;
+
+    fn count(&self, n: u64, a: u64) -> u64 {
+        match n {
+            0 => a,
+            _ => self.count(n - 1, a + self.step()),
+        }
+    }
+}
+
+impl Countdown for Arith {
+    fn step(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::ops::Add for Arith {
+    type Output = Arith;
+
+    #[recursive]
+    fn add(self, other: Self) -> Self::Output {
+        match other.0 {
+            0 => self,
+            _ => Arith(self.0 + 1).add(Arith(other.0 - 1)),
+        }
+    }
+}
+
+struct Node {
+    value: u64,
+    next: Option<Box<Node>>,
+}
+
+impl Node {
+    #[recursive]
+    fn len(&self, a: usize) -> usize {
+        match &self.next {
+            Some(next) => next.len(a + 1),
+            None => a + 1,
+        }
+    }
+
+    #[recursive]
+    fn find(&self, value: &u64) -> Option<&Node> {
+        match &self.next {
+            _ if self.value == *value => Some(self),
+            Some(next) => next.find(value),
+            None => None,
+        }
+    }
+
+    #[recursive(stack)]
+    fn sum_values(&self) -> u64 {
+        match &self.next {
+            Some(next) => self.value + next.sum_values(),
+            None => self.value,
+        }
+    }
+
+    #[recursive]
+    fn doubled(&self) -> Node {
+        match &self.next {
+            Some(next) => Node {
+                value: self.value * 2,
+                next: Some(Box::new(next.doubled())),
+            },
+            None => Node {
+                value: self.value * 2,
+                next: None,
+            },
+        }
+    }
+
+    #[recursive]
+    fn last_mut(&mut self) -> &mut u64 {
+        match self.next {
+            Some(ref mut next) => next.last_mut(),
+            None => &mut self.value,
+        }
+    }
 }
 
 fn main() {
-    println!("Result: {}", sum(999_999, 0));
-    println!("Result: {}", factorial(10, 1));
-    println!("{}", repeat("*", 10, String::new()));
+    println!("Result: {}", sum(999_999));
+    println!("Result: {}", factorial(10));
+    println!("{}", repeat("*", 10));
+    println!("Result: {}", count(&vec![1; 999_999], &1));
+    println!("Result: {:?}", last(&[1, 2, 3], None));
+    let xs: Vec<u64> = (0..999_999).collect();
+    println!("Result: {:?}", search(&xs, (0, xs.len()), 777_777));
+    println!("Result: {}", collatz(837_799, 0));
+    println!("Result: {}", strip(&"ab".repeat(999_999), "ab").len());
+    println!("Result: {:?}", nth(0.., 999_999));
+    println!("Result: {}", apply(|x| x + 2, 0, 999_999));
+    println!("Result: {}", total(&xs, 0));
+    let tokens = vec!["1"; 999_999];
+    println!("Result: {:?}", parse_sum(&tokens, 0));
+    println!("Result: {:?}", parse_sum(&["1", "x"], 0).is_err());
+    println!("Result: {}", gcd(832_040, 514_229));
+    println!("Result: {}", triangle(999_999));
+    println!("Result: {}", digits(1_234_567));
+    println!("Result: {}", maximum(&xs));
+    println!("Result: {}", fibonacci(20));
+    println!("Result: {}", partitions(20, 20));
+    println!("Result: {}", compositions(16));
+    println!("Result: {}", staircase(20));
+    println!("Result: {}", squares(&xs).len());
+    println!("Result: {}", join(&[1, 2, 3]));
+    println!("Result: {:?}", skip_spaces(&" ".repeat(999_999)));
+    println!("Result: {} {}", is_even(999_999), is_odd(999_999));
+    let text = "a bc  d ".repeat(999_999);
+    println!("Result: {}", words::between(text.as_bytes(), 0));
+    println!("Result: {}", steps::down(999_999, 0));
 
-    let arith = Arith(12);
+    let mut arith = Arith(12);
     println!("Result: {}", arith.sum(10, 0));
+    arith.add(999_999);
+    println!("Result: {}", arith.0);
+    println!("Result: {}", Arith(0).into_sum(999_999));
+    println!("Result: {}", Box::new(Arith(1)).boxed_sum(999_999, 0));
+    println!("Result: {}", Rc::new(Arith(1)).shared_sum(999_999, 0));
+    println!("Result: {}", (Arith(1) + Arith(999_999)).0);
+    println!("Result: {}", Arith::triangle(999_999, 0).0);
+    println!("Result: {}", Arith(2).count(999_999, 0));
+    println!("Result: {} {}", Arith::even(999_999), Arith::odd(999_999));
+
+    let mut list = Node {
+        value: 0,
+        next: None,
+    };
+    for value in 1..10_000 {
+        let next = list.next.take();
+        list.next = Some(Box::new(Node { value, next }));
+    }
+    *list.last_mut() += 1;
+    println!("Result: {} {}", list.len(0), list.last_mut());
+    println!("Result: {:?}", list.find(&2).map(|node| node.value));
+    println!("Result: {}", list.sum_values());
+    println!("Result: {}", list.doubled().sum_values());
 }
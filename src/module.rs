@@ -0,0 +1,293 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{spanned::Spanned, visit::Visit, *};
+
+use crate::args::Args;
+use crate::group;
+use crate::target::CallTarget;
+use crate::warning::Warning;
+
+/// The functions of an inline module or `impl` block calling one another, by
+/// the index of the callees of each function.
+pub struct CallGraph {
+    pub calls: Vec<Vec<usize>>,
+}
+
+impl CallGraph {
+    /// Finds the calls between `functions`, wherever they are made in the body,
+    /// including the method calls on `self`.
+    pub fn new(functions: &[&ItemFn]) -> Self {
+        struct CallFinder<'a> {
+            functions: &'a [&'a ItemFn],
+            targets: &'a [CallTarget],
+            callees: Vec<usize>,
+        }
+
+        impl CallFinder<'_> {
+            fn add(&mut self, callee: Option<usize>) {
+                if let Some(callee) = callee {
+                    if !self.callees.contains(&callee) {
+                        self.callees.push(callee);
+                    }
+                }
+            }
+        }
+
+        impl<'ast> Visit<'ast> for CallFinder<'_> {
+            fn visit_expr_call(&mut self, node: &'ast ExprCall) {
+                let callee = self
+                    .targets
+                    .iter()
+                    .position(|target| target.resolve(&node.func, false));
+                self.add(callee);
+                visit::visit_expr_call(self, node);
+            }
+
+            fn visit_expr_method_call(&mut self, node: &'ast ExprMethodCall) {
+                if matches!(&*node.receiver, Expr::Path(path) if path.path.is_ident("self")) {
+                    let callee = self.functions.iter().position(|item_fn| {
+                        item_fn.sig.receiver().is_some() && item_fn.sig.ident == node.method
+                    });
+                    self.add(callee);
+                }
+                visit::visit_expr_method_call(self, node);
+            }
+
+            fn visit_item(&mut self, _node: &'ast Item) {}
+        }
+
+        let targets: Vec<_> = functions
+            .iter()
+            .map(|item_fn| CallTarget::new(item_fn, None))
+            .collect();
+        let calls = functions
+            .iter()
+            .map(|item_fn| {
+                let mut finder = CallFinder {
+                    functions,
+                    targets: &targets,
+                    callees: vec![],
+                };
+                finder.visit_block(&item_fn.block);
+                finder.callees
+            })
+            .collect();
+
+        CallGraph { calls }
+    }
+
+    /// The strongly connected components of the graph, the functions of each
+    /// of which all call one another, in the order of Tarjan's algorithm.
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        struct Tarjan<'a> {
+            calls: &'a [Vec<usize>],
+            index: Vec<Option<usize>>,
+            low: Vec<usize>,
+            stack: Vec<usize>,
+            on_stack: Vec<bool>,
+            next: usize,
+            components: Vec<Vec<usize>>,
+        }
+
+        impl Tarjan<'_> {
+            fn visit(&mut self, node: usize) {
+                self.index[node] = Some(self.next);
+                self.low[node] = self.next;
+                self.next += 1;
+                self.stack.push(node);
+                self.on_stack[node] = true;
+
+                for &callee in &self.calls[node] {
+                    match self.index[callee] {
+                        None => {
+                            self.visit(callee);
+                            self.low[node] = self.low[node].min(self.low[callee]);
+                        }
+                        Some(index) if self.on_stack[callee] => {
+                            self.low[node] = self.low[node].min(index);
+                        }
+                        Some(_) => {}
+                    }
+                }
+
+                if Some(self.low[node]) == self.index[node] {
+                    let mut component = vec![];
+                    while let Some(member) = self.stack.pop() {
+                        self.on_stack[member] = false;
+                        component.push(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+                    component.sort_unstable();
+                    self.components.push(component);
+                }
+            }
+        }
+
+        let len = self.calls.len();
+        let mut tarjan = Tarjan {
+            calls: &self.calls,
+            index: vec![None; len],
+            low: vec![0; len],
+            stack: vec![],
+            on_stack: vec![false; len],
+            next: 0,
+            components: vec![],
+        };
+        for node in 0..len {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+        tarjan.components
+    }
+}
+
+/// Expands `#[recursive]` on an inline module.
+///
+/// The functions of the module calling themselves are transformed as if they
+/// were annotated, with the options given to the module. Those calling each
+/// other in a cycle are transformed together, as a `recursive_group!`, with the
+/// same options but those keeping what is pending around the calls, which are
+/// errors there. Nested inline modules are expanded in the same way, and
+/// functions annotated on their own are left to their own attribute.
+pub fn expand(args: AttributeArgs, item_mod: ItemMod) -> Result<ItemMod> {
+    let parsed = parse_args(args.clone(), "a module")?;
+
+    let (brace, items) = match item_mod.content {
+        Some(content) => content,
+        None => {
+            return Err(Error::new(
+                item_mod.span(),
+                "`#[recursive]` can only be applied to inline modules, whose functions it sees",
+            ))
+        }
+    };
+
+    let mut report = vec![];
+    let mut slots: Vec<Option<TokenStream>> = vec![];
+    let mut functions: Vec<(usize, ItemFn)> = vec![];
+    for item in items {
+        match item {
+            Item::Fn(item_fn) if !is_annotated(&item_fn.attrs) => {
+                functions.push((slots.len(), item_fn));
+                slots.push(None);
+            }
+            Item::Mod(item_mod) if item_mod.content.is_some() => {
+                let expanded = expand(args.clone(), item_mod.clone());
+                slots.push(Some(crate::expanded_or_kept(expanded, item_mod)));
+            }
+            item => slots.push(Some(item.into_token_stream())),
+        }
+    }
+
+    let (indices, item_fns): (Vec<_>, Vec<_>) = functions.into_iter().unzip();
+    let graph = CallGraph::new(&item_fns.iter().collect::<Vec<_>>());
+    let expanded = expand_components(&args, &item_fns, &graph, &mut report);
+    for (slot, tokens) in indices.into_iter().zip(expanded) {
+        slots[slot] = Some(tokens);
+    }
+
+    if parsed.debug {
+        print_report(&format!("mod {}", item_mod.ident), &report);
+    }
+
+    let items = slots.into_iter().flatten();
+    let content = Item::Verbatim(quote!(#(#items)*));
+    Ok(ItemMod {
+        content: Some((brace, vec![content])),
+        ..item_mod
+    })
+}
+
+/// Transforms `functions` by the strongly connected components of their call
+/// `graph`: those calling themselves as if they were annotated with `args`, and
+/// those calling each other together, as a `recursive_group!`.
+///
+/// The code of a whole component takes the place of its first function, and
+/// the others are replaced by nothing.
+pub fn expand_components(
+    args: &AttributeArgs,
+    functions: &[ItemFn],
+    graph: &CallGraph,
+    report: &mut Vec<String>,
+) -> Vec<TokenStream> {
+    let mut expanded = vec![TokenStream::new(); functions.len()];
+    for component in graph.components() {
+        let group: Vec<ItemFn> = component
+            .iter()
+            .map(|&index| functions[index].clone())
+            .collect();
+        let names = group
+            .iter()
+            .map(|item_fn| format!("`{}`", item_fn.sig.ident))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        expanded[component[0]] = match group.as_slice() {
+            [item_fn] if !graph.calls[component[0]].contains(&component[0]) => {
+                item_fn.to_token_stream()
+            }
+            [item_fn] => {
+                report.push(format!("{} calls itself", names));
+                crate::expanded_or_kept(crate::expand(args.clone(), item_fn.clone()), item_fn)
+            }
+            _ => match group::validate_group(&group) {
+                Ok(()) => {
+                    report.push(format!("{} call each other", names));
+                    crate::expanded_or_kept(group::expand(&group, args.clone()), quote!(#(#group)*))
+                }
+                // The functions are left as they are, which they would be
+                // without the attribute, with the warnings in the first of them
+                // so that they can be raised in an `impl` block too.
+                Err(error) => {
+                    let mut group = group;
+                    let warnings = error.into_iter().map(|error| {
+                        let warning = Warning {
+                            span: error.span(),
+                            message: format!(
+                                "{} call each other, but are not transformed: {}",
+                                names, error
+                            ),
+                        };
+                        parse_quote!(#warning;)
+                    });
+                    group[0].block.stmts.splice(0..0, warnings);
+                    quote!(#(#group)*)
+                }
+            },
+        };
+    }
+    expanded
+}
+
+/// Parses the options given to `#[recursive]` on the functions of `item` as a
+/// whole.
+pub fn parse_args(args: AttributeArgs, item: &str) -> Result<Args> {
+    let parsed = Args::parse(args)?;
+    if let Some(hidden) = parsed.acc.first() {
+        let message = format!(
+            "`acc(..)` names the parameters of a single function, so it cannot be given to {}",
+            item
+        );
+        return Err(Error::new(hidden.name.span(), message));
+    }
+    Ok(parsed)
+}
+
+/// Prints how the functions of `item` were transformed, given `debug`.
+pub fn print_report(item: &str, report: &[String]) {
+    if report.is_empty() {
+        println!("`{}`: no recursive functions", item);
+    } else {
+        println!("`{}`: {}", item, report.join("; "));
+    }
+}
+
+/// Whether a function has an attribute of its own, which it is left to.
+pub fn is_annotated(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(
+        |attr| matches!(attr.path.segments.last(), Some(segment) if segment.ident == "recursive"),
+    )
+}
@@ -0,0 +1,182 @@
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use quote::{quote, quote_spanned};
+use syn::{spanned::Spanned, visit_mut::VisitMut, *};
+
+/// How a method takes its receiver, which decides how the receiver of a
+/// recursive call is turned into the next iteration's `self`.
+pub enum ReceiverKind {
+    /// `&self` or `self: &Self`.
+    Shared,
+    /// `&mut self` or `self: &mut Self`.
+    Mutable,
+    /// `self`, `self: Box<Self>`, `self: Rc<Self>`, `self: &Box<Self>` and the
+    /// like.
+    Owned(Box<Type>),
+}
+
+impl ReceiverKind {
+    pub fn of(sig: &Signature) -> Option<Self> {
+        let (reference, ty) = match sig.receiver()? {
+            FnArg::Receiver(receiver) => (
+                receiver
+                    .reference
+                    .as_ref()
+                    .map(|_| receiver.mutability.is_some()),
+                parse_quote!(Self),
+            ),
+            FnArg::Typed(pt) => match &*pt.ty {
+                Type::Reference(reference) if is_self(&reference.elem) => {
+                    (Some(reference.mutability.is_some()), pt.ty.clone())
+                }
+                // `self: &Box<Self>` and the like are passed as is.
+                _ => (None, pt.ty.clone()),
+            },
+        };
+
+        Some(match reference {
+            Some(false) => ReceiverKind::Shared,
+            Some(true) => ReceiverKind::Mutable,
+            None => ReceiverKind::Owned(ty),
+        })
+    }
+
+    /// The type of `self`.
+    fn ty(&self) -> TokenStream {
+        match self {
+            ReceiverKind::Shared => quote!(&Self),
+            ReceiverKind::Mutable => quote!(&mut Self),
+            ReceiverKind::Owned(ty) => quote!(#ty),
+        }
+    }
+
+    /// Builds the next iteration's receiver out of the receiver expression of a
+    /// recursive method call, applying the auto-referencing of method calls.
+    ///
+    /// The result is bound with the type of `self`, which only type checks
+    /// through a deref coercion or an exact match, so a call on anything that
+    /// is not a `Self` is a hard error pointing at the receiver expression.
+    pub fn next_receiver(&self, expr: &Expr) -> Expr {
+        let span = expr.span();
+
+        let receiver = match self {
+            ReceiverKind::Shared if is_place(expr) => quote_spanned!(span=> &#expr),
+            ReceiverKind::Shared => quote_spanned!(span=> &*#expr),
+            ReceiverKind::Mutable => match expr {
+                // Most likely a `&mut` binding, which can only be reborrowed.
+                Expr::Path(_) => quote_spanned!(span=> &mut *#expr),
+                _ if is_place(expr) => quote_spanned!(span=> &mut #expr),
+                _ => quote_spanned!(span=> &mut *#expr),
+            },
+            ReceiverKind::Owned(_) => quote!(#expr),
+        };
+
+        self.bind(receiver, span)
+    }
+
+    /// Builds the next iteration's receiver out of the first argument of a
+    /// recursive call through a path such as `Self::f(node, ..)`, which is
+    /// passed as is.
+    pub fn receiver_arg(&self, expr: &Expr) -> Expr {
+        self.bind(quote!(#expr), expr.span())
+    }
+
+    fn bind(&self, receiver: TokenStream, span: Span) -> Expr {
+        let ty = self.ty();
+
+        Expr::Verbatim(quote_spanned! {span=> {
+            let receiver: #ty = #receiver;
+            receiver
+        }})
+    }
+}
+
+fn is_self(ty: &Type) -> bool {
+    match ty {
+        Type::Path(type_path) => type_path.qself.is_none() && type_path.path.is_ident("Self"),
+        Type::Paren(type_paren) => is_self(&type_paren.elem),
+        _ => false,
+    }
+}
+
+fn is_place(expr: &Expr) -> bool {
+    match expr {
+        Expr::Path(_) | Expr::Field(_) | Expr::Index(_) => true,
+        Expr::Unary(ExprUnary {
+            op: UnOp::Deref(_), ..
+        }) => true,
+        Expr::Paren(expr_paren) => is_place(&expr_paren.expr),
+        _ => false,
+    }
+}
+
+/// Renames every use of `self` in a function body.
+///
+/// `self` cannot be rebound with `let`, so the receiver is threaded through the
+/// loop state under a fresh name instead. Nested items have a `self` of their
+/// own and are left alone; closures capture the outer one and are renamed.
+pub struct ReceiverRenamer {
+    pub ident: Ident,
+}
+
+impl ReceiverRenamer {
+    /// The fresh name, pointing at the `self` it replaces.
+    fn renamed(&self, span: Span) -> Ident {
+        let mut ident = self.ident.clone();
+        ident.set_span(ident.span().located_at(span));
+        ident
+    }
+
+    fn rename_tokens(&self, tokens: TokenStream) -> TokenStream {
+        let mut tokens = tokens.into_iter().peekable();
+        let mut renamed = TokenStream::new();
+
+        while let Some(token) = tokens.next() {
+            let token = match token {
+                TokenTree::Ident(ident) if ident == "self" => {
+                    // `self::path` refers to the current module, not the receiver.
+                    match tokens.peek() {
+                        Some(TokenTree::Punct(punct)) if punct.as_char() == ':' => {
+                            TokenTree::Ident(ident)
+                        }
+                        _ => TokenTree::Ident(self.renamed(ident.span())),
+                    }
+                }
+                TokenTree::Group(group) => {
+                    let stream = self.rename_tokens(group.stream());
+                    let mut renamed_group = Group::new(group.delimiter(), stream);
+                    renamed_group.set_span(group.span());
+                    TokenTree::Group(renamed_group)
+                }
+                token => token,
+            };
+            renamed.extend(Some(token));
+        }
+
+        renamed
+    }
+}
+
+impl VisitMut for ReceiverRenamer {
+    fn visit_expr_path_mut(&mut self, node: &mut ExprPath) {
+        if node.qself.is_none() && node.path.is_ident("self") {
+            let span = node.path.segments[0].ident.span();
+            node.path = self.renamed(span).into();
+        }
+    }
+
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        match node {
+            // Syntax syn keeps as tokens, such as `let .. else` statements.
+            Expr::Verbatim(tokens) => *tokens = self.rename_tokens(tokens.clone()),
+            _ => visit_mut::visit_expr_mut(self, node),
+        }
+    }
+
+    fn visit_macro_mut(&mut self, node: &mut Macro) {
+        node.tokens = self.rename_tokens(node.tokens.clone());
+    }
+
+    fn visit_item_mut(&mut self, _node: &mut Item) {
+        // Nested items do not see the outer receiver.
+    }
+}
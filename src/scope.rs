@@ -0,0 +1,90 @@
+use proc_macro2::TokenStream;
+use syn::{visit::Visit, *};
+
+use crate::target::mentions;
+
+/// Whether `pat` binds a variable named `ident`.
+pub fn binds(pat: &Pat, ident: &Ident) -> bool {
+    struct BindingFinder<'a> {
+        ident: &'a Ident,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for BindingFinder<'_> {
+        fn visit_pat_ident(&mut self, node: &'ast PatIdent) {
+            self.found |= node.ident == *self.ident;
+            visit::visit_pat_ident(self, node);
+        }
+    }
+
+    let mut finder = BindingFinder {
+        ident,
+        found: false,
+    };
+    finder.visit_pat(pat);
+    finder.found
+}
+
+/// The variables `pat` binds.
+///
+/// A lone identifier starting with an uppercase letter is taken for a unit
+/// struct, an enum variant or a constant, as in `None`, rather than a binding.
+pub fn bindings(pat: &Pat) -> Vec<Ident> {
+    struct BindingCollector(Vec<Ident>);
+
+    impl<'ast> Visit<'ast> for BindingCollector {
+        fn visit_pat_ident(&mut self, node: &'ast PatIdent) {
+            let is_binding = node.subpat.is_some()
+                || node.by_ref.is_some()
+                || node.mutability.is_some()
+                || !node.ident.to_string().starts_with(char::is_uppercase);
+            if is_binding {
+                self.0.push(node.ident.clone());
+            }
+            visit::visit_pat_ident(self, node);
+        }
+    }
+
+    let mut collector = BindingCollector(vec![]);
+    collector.visit_pat(pat);
+    collector.0
+}
+
+/// The variables of `scope` that `code` uses, of which only the innermost of
+/// the same name is in scope.
+pub fn live(scope: Vec<Ident>, code: &TokenStream) -> Vec<Ident> {
+    let mut live: Vec<Ident> = vec![];
+    for ident in scope.into_iter().rev() {
+        if !live.contains(&ident) && mentions(code.clone(), &ident.to_string()) {
+            live.push(ident);
+        }
+    }
+    live.reverse();
+    live
+}
+
+/// Whether a block declares an item named `ident` in the value namespace.
+///
+/// Items are visible in the whole block they are declared in, not only after
+/// their declaration, so they shadow the annotated function from the start.
+pub fn declares(stmts: &[Stmt], ident: &Ident) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Stmt::Item(Item::Fn(item_fn)) => item_fn.sig.ident == *ident,
+        Stmt::Item(Item::Const(item_const)) => item_const.ident == *ident,
+        Stmt::Item(Item::Static(item_static)) => item_static.ident == *ident,
+        Stmt::Item(Item::Use(item_use)) => imports(&item_use.tree, ident),
+        _ => false,
+    })
+}
+
+/// Whether a `use` tree brings a name `ident` into scope. Glob imports cannot
+/// be resolved and are assumed not to.
+fn imports(tree: &UseTree, ident: &Ident) -> bool {
+    match tree {
+        UseTree::Path(use_path) => imports(&use_path.tree, ident),
+        UseTree::Name(use_name) => use_name.ident == *ident,
+        UseTree::Rename(use_rename) => use_rename.rename == *ident,
+        UseTree::Glob(_) => false,
+        UseTree::Group(use_group) => use_group.items.iter().any(|tree| imports(tree, ident)),
+    }
+}
@@ -0,0 +1,357 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::*;
+
+use crate::hoist::is_variable;
+use crate::let_else::LetElse;
+use crate::scope;
+use crate::tail;
+use crate::RecursionTransformer;
+
+/// The frames of the calls in progress, given `stack`.
+///
+/// The body is split at the recursive calls it makes on its way to its result.
+/// The code up to the first call runs when the function is called, and the
+/// rest of the body after each call is a continuation, which runs once the call
+/// has returned. Making a call pushes a frame, holding the variables the
+/// continuation uses, onto a stack on the heap. The value a call returns is
+/// handed to the continuation of the frame on top of it, until there is none.
+///
+/// The frames are variants of an enum generic over the types of the variables,
+/// which are inferred rather than named.
+pub struct Frames {
+    continuations: Vec<Continuation>,
+    stack: Ident,
+    frame: Ident,
+    /// The value returned by the last call.
+    pub value: Ident,
+}
+
+/// The rest of the body after a recursive call.
+struct Continuation {
+    /// The variables the continuation uses, which are kept in the frame.
+    live: Vec<Ident>,
+    /// The continuation, starting with the binding of the call's value.
+    code: TokenStream,
+}
+
+impl Frames {
+    pub fn new() -> Self {
+        let span = Span::mixed_site();
+        Frames {
+            continuations: vec![],
+            stack: Ident::new("stack", span),
+            frame: Ident::new("Frame", span),
+            value: Ident::new("result", span),
+        }
+    }
+
+    fn variant(index: usize) -> Ident {
+        Ident::new(&format!("Continuation{}", index), Span::mixed_site())
+    }
+
+    /// The number of calls in progress, those waiting in a frame and the
+    /// current one.
+    pub fn depth(&self) -> TokenStream {
+        let stack = &self.stack;
+        quote!(#stack.len() as u64 + 1)
+    }
+
+    /// Declares the enum of frames and the stack.
+    pub fn declare(&self) -> TokenStream {
+        let Frames { stack, frame, .. } = self;
+        let count = self.continuations.len();
+        let params: Vec<_> = (0..count)
+            .map(|index| Ident::new(&format!("T{}", index), Span::mixed_site()))
+            .collect();
+        let variants = (0..count).map(Frames::variant);
+        let inferred = params.iter().map(|_| quote!(_));
+
+        quote! {
+            enum #frame<#(#params),*> {
+                #(#variants(#params),)*
+            }
+            let mut #stack: Vec<#frame<#(#inferred),*>> = Vec::new();
+        }
+    }
+
+    /// Hands the value of a call to the continuations of the frames, which may
+    /// make calls of their own, returning it once the stack is empty.
+    ///
+    /// A continuation is checked after those pushing its frame, so that the
+    /// types of its variables are known by then.
+    pub fn resume(&self, label: &Lifetime) -> TokenStream {
+        let Frames {
+            stack,
+            frame,
+            value,
+            ..
+        } = self;
+
+        if self.continuations.is_empty() {
+            return quote!(return #value;);
+        }
+
+        let arms = self
+            .continuations
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, continuation)| {
+                let variant = Frames::variant(index);
+                let Continuation { live, code } = continuation;
+                quote! {
+                    Some(#frame::#variant((#(mut #live,)*))) => #label: { #code }
+                }
+            });
+
+        quote! {
+            loop {
+                #[allow(unused_mut)]
+                {
+                    #value = match #stack.pop() {
+                        #(#arms)*
+                        None => return #value,
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl RecursionTransformer {
+    /// Splits the body into the code running up to the first recursive call
+    /// and continuations, given the variables in `scope`.
+    pub fn split_body(&mut self, block: &Block, scope: Vec<Ident>) -> TokenStream {
+        self.split_stmts(&block.stmts, scope)
+    }
+
+    /// Splits statements at the first recursive call, whose value is bound to
+    /// a variable. The last expression is in tail position.
+    fn split_stmts(&mut self, stmts: &[Stmt], scope: Vec<Ident>) -> TokenStream {
+        let shadows = self.declares(stmts);
+
+        self.scoped(shadows, |this| {
+            let mut scope = scope;
+            let mut code = TokenStream::new();
+
+            for (index, stmt) in stmts.iter().enumerate() {
+                let rest = &stmts[index + 1..];
+
+                match stmt {
+                    Stmt::Expr(expr) if rest.is_empty() => {
+                        code.extend(this.split_tail(expr, scope));
+                        break;
+                    }
+                    // Whatever comes after is unreachable.
+                    Stmt::Semi(
+                        Expr::Return(ExprReturn {
+                            expr: Some(expr), ..
+                        }),
+                        _,
+                    ) => {
+                        code.extend(this.split_tail(expr, scope));
+                        break;
+                    }
+                    Stmt::Item(_) => stmt.to_tokens(&mut code),
+                    _ => {
+                        let mut stmt = stmt.clone();
+                        let calls = this.hoist_stmt(&mut stmt);
+                        if !calls.is_empty() {
+                            let rest = Some(stmt).into_iter().chain(rest.iter().cloned());
+                            code.extend(this.suspend(calls, rest.collect(), scope));
+                            break;
+                        }
+
+                        this.finish_stmt(&mut stmt);
+                        stmt.to_tokens(&mut code);
+
+                        let pat = match &stmt {
+                            Stmt::Local(local) => Some(local.pat.clone()),
+                            Stmt::Semi(Expr::Verbatim(tokens), _) => {
+                                parse2::<LetElse>(tokens.clone())
+                                    .ok()
+                                    .map(|let_else| let_else.pat)
+                            }
+                            _ => None,
+                        };
+                        if let Some(pat) = pat {
+                            scope.extend(scope::bindings(&pat));
+                            this.shadowed |= this.binds(&pat);
+                        }
+                    }
+                }
+            }
+
+            code
+        })
+    }
+
+    /// Splits an expression in tail position, whose value is the result of the
+    /// call.
+    fn split_tail(&mut self, expr: &Expr, scope: Vec<Ident>) -> TokenStream {
+        match expr {
+            Expr::If(expr_if) => {
+                let mut expr_if = expr_if.clone();
+                let calls = self.hoist(&mut expr_if.cond);
+                if !calls.is_empty() {
+                    return self.suspend(calls, vec![Stmt::Expr(Expr::If(expr_if))], scope);
+                }
+                self.finish_expr(&mut expr_if.cond);
+
+                let ExprIf {
+                    attrs,
+                    cond,
+                    then_branch,
+                    else_branch,
+                    ..
+                } = &expr_if;
+                let mut then_scope = scope.clone();
+                if let Expr::Let(expr_let) = &**cond {
+                    then_scope.extend(scope::bindings(&expr_let.pat));
+                }
+                let shadows = self.cond_binds(cond);
+                let then_branch = self.scoped(shadows, |this| {
+                    this.split_stmts(&then_branch.stmts, then_scope)
+                });
+                let else_branch = else_branch.as_ref().map(|(_, else_branch)| {
+                    let else_branch = self.split_tail(else_branch, scope);
+                    quote!(else { #else_branch })
+                });
+
+                quote!(#(#attrs)* if #cond { #then_branch } #else_branch)
+            }
+            Expr::Match(expr_match) => {
+                let mut expr_match = expr_match.clone();
+                let calls = self.hoist(&mut expr_match.expr);
+                if !calls.is_empty() {
+                    return self.suspend(calls, vec![Stmt::Expr(Expr::Match(expr_match))], scope);
+                }
+                self.finish_expr(&mut expr_match.expr);
+
+                let ExprMatch { attrs, expr, .. } = &expr_match;
+                let arms: Vec<_> = expr_match
+                    .arms
+                    .iter()
+                    .map(|arm| {
+                        let Arm { attrs, pat, .. } = arm;
+                        let mut arm_scope = scope.clone();
+                        arm_scope.extend(scope::bindings(pat));
+
+                        self.scoped(self.binds(pat), |this| {
+                            let guard = arm.guard.clone().map(|(if_token, mut guard)| {
+                                this.finish_expr(&mut guard);
+                                quote!(#if_token #guard)
+                            });
+                            let body = this.split_tail(&arm.body, arm_scope);
+                            quote!(#(#attrs)* #pat #guard => { #body })
+                        })
+                    })
+                    .collect();
+
+                quote!(#(#attrs)* match #expr { #(#arms)* })
+            }
+            Expr::Block(ExprBlock {
+                label: None, block, ..
+            }) => {
+                let block = self.split_stmts(&block.stmts, scope);
+                quote!({ #block })
+            }
+            Expr::Paren(expr_paren) => self.split_tail(&expr_paren.expr, scope),
+            Expr::Return(ExprReturn {
+                expr: Some(expr), ..
+            }) => self.split_tail(expr, scope),
+            _ => {
+                let mut expr = expr.clone();
+                let mut calls = self.hoist(&mut expr);
+
+                match calls.pop() {
+                    // A call whose value is the result is a tail call, which
+                    // reuses the frame of the current one.
+                    Some((result, call)) if is_variable(&expr, &result) => {
+                        if calls.is_empty() {
+                            self.tail_call(&call)
+                        } else {
+                            self.suspend(calls, vec![Stmt::Expr(call)], scope)
+                        }
+                    }
+                    Some(last) => {
+                        calls.push(last);
+                        self.suspend(calls, vec![Stmt::Expr(expr)], scope)
+                    }
+                    None => {
+                        self.finish_expr(&mut expr);
+                        expr.into_token_stream()
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes the first of the hoisted `calls`, with the other calls and `rest`
+    /// as its continuation.
+    fn suspend(
+        &mut self,
+        calls: Vec<(Ident, Expr)>,
+        rest: Vec<Stmt>,
+        scope: Vec<Ident>,
+    ) -> TokenStream {
+        let mut calls = calls.into_iter();
+        let (result, call) = match calls.next() {
+            Some(first) => first,
+            None => return self.split_stmts(&rest, scope),
+        };
+        if let Some(span) = tail::marker(&call) {
+            self.report_misplaced(span);
+        }
+
+        let mut inner_scope = scope.clone();
+        inner_scope.push(result.clone());
+        let remaining: Vec<_> = calls.collect();
+        let inner = if remaining.is_empty() {
+            self.split_stmts(&rest, inner_scope)
+        } else {
+            self.suspend(remaining, rest, inner_scope)
+        };
+
+        let frames = self.frames.as_mut().expect("frames are split");
+        let value = &frames.value;
+        let code = quote! {
+            let #result = #value;
+            #inner
+        };
+
+        let live = scope::live(scope, &code);
+
+        let index = frames.continuations.len();
+        let variant = Frames::variant(index);
+        let frame_value = quote!(#(#live,)*);
+        frames.continuations.push(Continuation { live, code });
+
+        let Frames { stack, frame, .. } = &*frames;
+        let (stack, frame) = (stack.clone(), frame.clone());
+        let state = self.next_state(&call).expect("hoisted calls are recursive");
+        let acc = &self.acc;
+        let label = &self.label;
+        let next = Ident::new("next", Span::mixed_site());
+
+        // The arguments are evaluated before the variables move into the frame.
+        quote! {{
+            let #next = #state;
+            #stack.push(#frame::#variant((#frame_value)));
+            #acc = #next;
+            continue #label;
+        }}
+    }
+
+    fn tail_call(&mut self, call: &Expr) -> TokenStream {
+        let state = self.next_state(call).expect("hoisted calls are recursive");
+        let acc = &self.acc;
+        let label = &self.label;
+
+        quote! {{
+            #acc = #state;
+            continue #label;
+        }}
+    }
+}
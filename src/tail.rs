@@ -0,0 +1,297 @@
+use proc_macro2::{Delimiter, Group, Ident, Span, TokenStream, TokenTree};
+use quote::{quote_spanned, ToTokens};
+use std::mem;
+use syn::{spanned::Spanned, visit_mut::VisitMut, *};
+
+use crate::let_else::LetElse;
+use crate::RecursionTransformer;
+
+/// The attribute marking the calls made with `tail!`, which is removed from
+/// every one of them.
+const MARK: &str = "recursive_tail";
+
+impl RecursionTransformer {
+    /// Rewrites `tail!(f(..))` into `f(..)`, with the call marked so that it
+    /// is an error for it to be left as it is, or to be anywhere but in tail
+    /// position.
+    ///
+    /// Like `return`, the marker cannot be used in a closure or an async
+    /// block, which it would return from.
+    pub fn mark_tail_calls(&mut self, block: &mut Block) {
+        let mut markers = Markers {
+            names: self.names().iter().map(ToString::to_string).collect(),
+            nested: false,
+            errors: vec![],
+        };
+        markers.visit_block_mut(block);
+        self.errors.extend(markers.errors);
+    }
+
+    /// Reports a call made with `tail!` that is left as it is, which is an
+    /// error whether or not it is a recursive call.
+    pub fn report_marked(&mut self, span: Span, recursive: bool) {
+        let message = if !recursive {
+            takes_call(&self.names())
+        } else if self.frames.is_some() {
+            "this call is made with `tail!`, but `stack` only eliminates the calls in the \
+             branches leading to the function's result, not in loops or other expressions"
+                .to_string()
+        } else if self.continuations.is_some() {
+            "this call is made with `tail!`, but `cps` does not eliminate calls in loops"
+                .to_string()
+        } else {
+            "this call is made with `tail!`, but cannot be eliminated".to_string()
+        };
+        self.errors.push(Error::new(span, message));
+    }
+
+    /// Reports a call made with `tail!` whose value is not the function's
+    /// result, so that it cannot be made as a tail call.
+    pub fn report_misplaced(&mut self, span: Span) {
+        let message = "`tail!` is not in tail position, as the value of this call is used \
+                       rather than being the function's result";
+        self.errors.push(Error::new(span, message));
+    }
+}
+
+/// The span of the `tail!` a call was made with, if any.
+pub fn marker(expr: &Expr) -> Option<Span> {
+    let attrs = match expr {
+        Expr::Call(expr_call) => &expr_call.attrs,
+        Expr::MethodCall(expr_method_call) => &expr_method_call.attrs,
+        _ => return None,
+    };
+    attrs
+        .iter()
+        .find(|attr| attr.path.is_ident(MARK))
+        .map(|attr| attr.path.span())
+}
+
+/// Removes the mark of a call made with `tail!`, returning the span of the
+/// `tail!` if it had one.
+pub fn unmark(expr: &mut Expr) -> Option<Span> {
+    let span = marker(expr)?;
+    let attrs = match expr {
+        Expr::Call(expr_call) => &mut expr_call.attrs,
+        Expr::MethodCall(expr_method_call) => &mut expr_method_call.attrs,
+        _ => return None,
+    };
+    attrs.retain(|attr| !attr.path.is_ident(MARK));
+    Some(span)
+}
+
+/// The message for a `tail!` wrapping anything but a call to one of `names`.
+fn takes_call<T: ToString>(names: &[T]) -> String {
+    let names: Vec<_> = names
+        .iter()
+        .map(|name| format!("`{}`", name.to_string()))
+        .collect();
+    match names.as_slice() {
+        [name] => format!("`tail!` takes a call to {}", name),
+        _ => format!("`tail!` takes a call to one of {}", names.join(", ")),
+    }
+}
+
+fn is_marker(mac: &Macro) -> bool {
+    mac.path.is_ident("tail")
+}
+
+/// Rewrites the `tail!` markers of a body, the statements among them included.
+struct Markers {
+    names: Vec<String>,
+    nested: bool,
+    errors: Vec<Error>,
+}
+
+impl Markers {
+    /// The call a marker wraps, marked, which is its value in a closure or an
+    /// async block as well.
+    fn marked_call(&mut self, mac: &Macro) -> Option<Expr> {
+        let mut call = match mac.parse_body::<Expr>() {
+            Ok(call) => call,
+            Err(_) => {
+                self.errors
+                    .push(Error::new(mac.span(), takes_call(&self.names)));
+                return None;
+            }
+        };
+
+        let attrs = match &mut call {
+            Expr::Call(expr_call) => &mut expr_call.attrs,
+            Expr::MethodCall(expr_method_call) => &mut expr_method_call.attrs,
+            // An async function's result is its call, awaited.
+            Expr::Await(ExprAwait { base, .. }) => match &mut **base {
+                Expr::Call(expr_call) => &mut expr_call.attrs,
+                Expr::MethodCall(expr_method_call) => &mut expr_method_call.attrs,
+                _ => {
+                    self.errors
+                        .push(Error::new(call.span(), takes_call(&self.names)));
+                    return None;
+                }
+            },
+            _ => {
+                self.errors
+                    .push(Error::new(call.span(), takes_call(&self.names)));
+                return None;
+            }
+        };
+
+        if self.nested {
+            let message = format!(
+                "`tail!` cannot be used in a closure or an async block, which it would return \
+                 from rather than from `{}`",
+                self.names[0]
+            );
+            self.errors.push(Error::new(mac.path.span(), message));
+            return Some(call);
+        }
+        let span = mac.path.span().resolved_at(Span::mixed_site());
+        let mark = Ident::new(MARK, span);
+        attrs.push(parse_quote!(#[#mark]));
+        Some(call)
+    }
+}
+
+impl VisitMut for Markers {
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        match node {
+            Expr::Macro(expr_macro) if is_marker(&expr_macro.mac) => {
+                if let Some(call) = self.marked_call(&expr_macro.mac) {
+                    *node = call;
+                }
+            }
+            Expr::Closure(_) | Expr::Async(_) => {
+                let outer = mem::replace(&mut self.nested, true);
+                visit_mut::visit_expr_mut(self, node);
+                self.nested = outer;
+            }
+            _ => visit_mut::visit_expr_mut(self, node),
+        }
+    }
+
+    fn visit_stmt_mut(&mut self, node: &mut Stmt) {
+        match node {
+            // `tail!(..);` is an item, unless it is followed by an operator.
+            Stmt::Item(Item::Macro(ItemMacro {
+                ident: None,
+                attrs,
+                mac,
+                semi_token,
+            })) if is_marker(mac) => {
+                let expr = Expr::Macro(ExprMacro {
+                    attrs: mem::take(attrs),
+                    mac: mac.clone(),
+                });
+                *node = match semi_token {
+                    Some(semi_token) => Stmt::Semi(expr, *semi_token),
+                    None => Stmt::Expr(expr),
+                };
+                visit_mut::visit_stmt_mut(self, node);
+            }
+            Stmt::Semi(Expr::Verbatim(tokens), _) => {
+                if let Ok(mut let_else) = parse2::<LetElse>(tokens.clone()) {
+                    self.visit_expr_mut(&mut let_else.init);
+                    self.visit_block_mut(&mut let_else.diverge);
+                    *tokens = let_else.to_token_stream();
+                }
+            }
+            _ => visit_mut::visit_stmt_mut(self, node),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _node: &mut Item) {
+        // Nested items are functions of their own.
+    }
+}
+
+/// Rewrites `become f(..)` into `return tail!(f(..))`, as syn cannot parse
+/// `become`.
+///
+/// Stable Rust rejects `become` in the items given to attributes, even if they
+/// rewrite it, so there it is only understood in `recursive_group!`, whose
+/// input is never parsed by the compiler.
+///
+/// The expression after `become` ends at the first `,` or `;` at which it
+/// parses, or at the end of the enclosing group.
+pub fn rewrite_become(tokens: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut rewritten = TokenStream::new();
+    let mut index = 0;
+
+    while index < tokens.len() {
+        match &tokens[index] {
+            TokenTree::Ident(ident) if ident == "become" => {
+                match become_operand(&tokens[index + 1..]) {
+                    Some(len) => {
+                        let operand: TokenStream =
+                            tokens[index + 1..index + 1 + len].iter().cloned().collect();
+                        let operand = rewrite_become(operand);
+                        rewritten.extend(quote_spanned!(ident.span()=> return tail!(#operand)));
+                        index += 1 + len;
+                        continue;
+                    }
+                    None => rewritten.extend(Some(tokens[index].clone())),
+                }
+            }
+            TokenTree::Group(group) => {
+                let mut rewritten_group =
+                    Group::new(group.delimiter(), rewrite_become(group.stream()));
+                rewritten_group.set_span(group.span());
+                rewritten.extend(Some(TokenTree::Group(rewritten_group)));
+            }
+            token => rewritten.extend(Some(token.clone())),
+        }
+        index += 1;
+    }
+
+    rewritten
+}
+
+/// Rewrites `tail!(f(..))` into `f(..)`, in the items kept as they are when
+/// they cannot be transformed, so that the markers are not reported as
+/// unknown macros on top of the errors.
+pub fn strip_markers(tokens: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut stripped = TokenStream::new();
+    let mut index = 0;
+
+    while index < tokens.len() {
+        match &tokens[index..] {
+            [TokenTree::Ident(ident), TokenTree::Punct(punct), TokenTree::Group(group), ..]
+                if ident == "tail" && punct.as_char() == '!' =>
+            {
+                let mut call = Group::new(Delimiter::None, strip_markers(group.stream()));
+                call.set_span(group.span());
+                stripped.extend(Some(TokenTree::Group(call)));
+                index += 3;
+                continue;
+            }
+            [TokenTree::Group(group), ..] => {
+                let mut stripped_group =
+                    Group::new(group.delimiter(), strip_markers(group.stream()));
+                stripped_group.set_span(group.span());
+                stripped.extend(Some(TokenTree::Group(stripped_group)));
+            }
+            [token, ..] => stripped.extend(Some(token.clone())),
+            [] => {}
+        }
+        index += 1;
+    }
+
+    stripped
+}
+
+/// The number of tokens of the expression after `become`.
+fn become_operand(tokens: &[TokenTree]) -> Option<usize> {
+    let ends = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| matches!(token, TokenTree::Punct(punct) if punct.as_char() == ',' || punct.as_char() == ';'))
+        .map(|(index, _)| index)
+        .chain(Some(tokens.len()));
+
+    ends.into_iter().find(|&end| {
+        let operand: TokenStream = tokens[..end].iter().cloned().collect();
+        end > 0 && parse2::<Expr>(operand).is_ok()
+    })
+}
@@ -0,0 +1,110 @@
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::*;
+
+use crate::utils::SignatureExtensions;
+
+/// Decides which callees of a call expression are the annotated function.
+pub struct CallTarget {
+    sig: Signature,
+    /// Whether the function lives in an `impl` or `trait` block, where it is
+    /// reached through `Self` and never by its bare name.
+    associated: bool,
+    /// The module the function is declared in, given as `module(crate::m)`,
+    /// as the macro cannot tell which module it expands in.
+    module: Option<Path>,
+}
+
+impl CallTarget {
+    pub fn new(item_fn: &ItemFn, module: Option<&Path>) -> Self {
+        let associated = item_fn.sig.receiver().is_some()
+            || mentions(item_fn.sig.to_token_stream(), "Self")
+            || mentions(item_fn.block.to_token_stream(), "Self");
+
+        CallTarget {
+            sig: item_fn.sig.clone(),
+            associated,
+            module: module.cloned(),
+        }
+    }
+
+    pub fn ident(&self) -> &Ident {
+        &self.sig.ident
+    }
+
+    /// Whether the callee of a call expression is the annotated function.
+    ///
+    /// Free functions are recognised as `f` and `self::f`, and as `crate::m::f`
+    /// where `crate::m` is the module given with `module(..)`; associated
+    /// functions as `Self::f` and `<Self>::f`. Anything else, including other
+    /// module paths, closures, fields and parenthesised callees, is an ordinary
+    /// call, and so is a bare `f` where a local binding or item has `shadowed`
+    /// the function.
+    pub fn resolve(&self, func: &Expr, shadowed: bool) -> bool {
+        let (qself, path) = match func {
+            Expr::Path(ExprPath { qself, path, .. }) => (qself, path),
+            _ => return false,
+        };
+
+        let last = match path.segments.last() {
+            Some(last) => last,
+            None => return false,
+        };
+        if last.ident != self.sig.ident || !self.sig.is_own_instantiation(&last.arguments) {
+            return false;
+        }
+
+        let prefix: Vec<_> = path
+            .segments
+            .iter()
+            .take(path.segments.len() - 1)
+            .map(|segment| (&segment.ident, &segment.arguments))
+            .collect();
+
+        match (qself, prefix.as_slice()) {
+            // `<Self>::f`
+            (Some(qself), []) if self.associated && qself.position == 0 => {
+                matches!(&*qself.ty, Type::Path(type_path) if type_path.path.is_ident("Self"))
+            }
+            (Some(_), _) => false,
+            // `Self::f`
+            (None, [(ident, PathArguments::None)]) if self.associated && *ident == "Self" => true,
+            _ if self.associated => false,
+            // `f`
+            (None, []) => path.leading_colon.is_none() && !shadowed,
+            // `self::f`
+            (None, [(ident, PathArguments::None)])
+                if path.leading_colon.is_none() && *ident == "self" =>
+            {
+                true
+            }
+            // `crate::m::f`, only for the module the function is declared in
+            (None, segments) => match &self.module {
+                Some(module) if path.leading_colon.is_none() => {
+                    segments.len() == module.segments.len()
+                        && segments.iter().zip(&module.segments).all(
+                            |((ident, arguments), segment)| {
+                                *ident == &segment.ident && matches!(arguments, PathArguments::None)
+                            },
+                        )
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether the function's name appears in `tokens`, such as the body of a
+    /// macro invocation.
+    pub fn mentioned_in(&self, tokens: TokenStream) -> bool {
+        mentions(tokens, &self.sig.ident.to_string())
+    }
+}
+
+/// Whether the identifier `name` appears in `tokens`, however deeply nested.
+pub fn mentions(tokens: TokenStream, name: &str) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => ident == name,
+        TokenTree::Group(group) => mentions(group.stream(), name),
+        _ => false,
+    })
+}
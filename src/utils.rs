@@ -1,15 +1,22 @@
-use syn::*;
+use syn::{visit::Visit, *};
 
 pub trait SignatureExtensions {
     fn split_inputs(&self) -> (Vec<Pat>, Vec<Type>);
     fn extract_return_type(&self) -> Type;
+    fn nameable_return_type(&self) -> Option<Type>;
+    fn receiver_pat(&self) -> Option<PatIdent>;
+    fn is_own_instantiation(&self, arguments: &PathArguments) -> bool;
 }
 
 impl SignatureExtensions for Signature {
+    /// Splits the non-receiver inputs into their patterns and types.
     fn split_inputs(&self) -> (Vec<Pat>, Vec<Type>) {
+        let receiver = self.receiver().cloned();
+
         self.inputs
             .clone()
             .into_iter()
+            .filter(|arg| Some(arg) != receiver.as_ref())
             .fold((vec![], vec![]), |mut acc, arg| match arg {
                 FnArg::Typed(pt) => {
                     acc.0.push(*pt.pat);
@@ -26,4 +33,92 @@ impl SignatureExtensions for Signature {
             ReturnType::Type(_, return_type) => *return_type,
         }
     }
+
+    /// The return type, unless it is (or contains) an `impl Trait`, which
+    /// cannot be written anywhere but in the signature.
+    fn nameable_return_type(&self) -> Option<Type> {
+        let return_type = self.extract_return_type();
+
+        if is_nameable(&return_type) {
+            Some(return_type)
+        } else {
+            None
+        }
+    }
+
+    /// The pattern binding `self`, for every receiver form: `self`, `mut self`,
+    /// `&self`, `&mut self` and `self: Box<Self>` alike.
+    fn receiver_pat(&self) -> Option<PatIdent> {
+        match self.receiver()? {
+            FnArg::Receiver(receiver) => Some(PatIdent {
+                attrs: vec![],
+                by_ref: None,
+                mutability: match receiver.reference {
+                    Some(_) => None,
+                    None => receiver.mutability,
+                },
+                ident: Ident::from(receiver.self_token),
+                subpat: None,
+            }),
+            FnArg::Typed(pt) => match &*pt.pat {
+                Pat::Ident(pat_ident) => Some(pat_ident.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether the generic `arguments` of a call instantiate the function with
+    /// its own type and const parameters, which is the only case in which the
+    /// call can reuse the loop state of the current one.
+    fn is_own_instantiation(&self, arguments: &PathArguments) -> bool {
+        let args = match arguments {
+            PathArguments::None => return true,
+            PathArguments::AngleBracketed(arguments) => &arguments.args,
+            PathArguments::Parenthesized(_) => return false,
+        };
+
+        let params = self.generics.params.iter().filter_map(|param| match param {
+            GenericParam::Type(type_param) => Some(&type_param.ident),
+            GenericParam::Const(const_param) => Some(&const_param.ident),
+            GenericParam::Lifetime(_) => None,
+        });
+        let args = args
+            .iter()
+            .filter(|arg| !matches!(arg, GenericArgument::Lifetime(_)));
+
+        params.zip(args).all(|(param, arg)| match arg {
+            GenericArgument::Type(Type::Infer(_)) => true,
+            GenericArgument::Type(Type::Path(type_path)) => {
+                type_path.qself.is_none() && type_path.path.is_ident(param)
+            }
+            GenericArgument::Const(Expr::Path(expr_path)) => {
+                expr_path.qself.is_none() && expr_path.path.is_ident(param)
+            }
+            GenericArgument::Const(Expr::Block(expr_block)) => {
+                match expr_block.block.stmts.as_slice() {
+                    [Stmt::Expr(Expr::Path(expr_path))] => {
+                        expr_path.qself.is_none() && expr_path.path.is_ident(param)
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        })
+    }
+}
+
+/// Whether a type can be written in the body, i.e. does not contain an
+/// `impl Trait`.
+pub fn is_nameable(ty: &Type) -> bool {
+    struct ImplTraitFinder(bool);
+
+    impl<'ast> Visit<'ast> for ImplTraitFinder {
+        fn visit_type_impl_trait(&mut self, _node: &'ast TypeImplTrait) {
+            self.0 = true;
+        }
+    }
+
+    let mut finder = ImplTraitFinder(false);
+    finder.visit_type(ty);
+    !finder.0
 }
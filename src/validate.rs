@@ -0,0 +1,50 @@
+use syn::{spanned::Spanned, *};
+
+/// Rejects the functions that cannot be turned into a loop, with an error on
+/// the offending part of the function.
+pub fn validate(item_fn: &ItemFn) -> Result<()> {
+    let mut errors = vec![];
+
+    if let Some(variadic) = &item_fn.sig.variadic {
+        errors.push(Error::new(
+            variadic.span(),
+            "`#[recursive]` does not support variadic functions",
+        ));
+    }
+
+    if item_fn.block.stmts.is_empty() {
+        errors.push(Error::new(
+            item_fn.block.span(),
+            "`#[recursive]` function has an empty body and never calls itself",
+        ));
+    }
+
+    combine(errors)
+}
+
+/// Rejects a `const fn` that would be transformed in `mode`, whose expansion
+/// calls functions that are not `const`, with an error on its `const`.
+pub fn validate_const(sig: &Signature, mode: Option<&str>) -> Result<()> {
+    match (&sig.constness, mode) {
+        (Some(constness), Some(mode)) => Err(Error::new(
+            constness.span(),
+            format!(
+                "`#[recursive]` cannot transform a `const fn` {}, as the expansion is not `const`",
+                mode
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Combines `errors` into a single error, if there are any.
+pub fn combine(errors: impl IntoIterator<Item = Error>) -> Result<()> {
+    let mut errors = errors.into_iter();
+    match errors.next() {
+        Some(mut error) => {
+            errors.for_each(|other| error.combine(other));
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
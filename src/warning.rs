@@ -0,0 +1,32 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote_spanned, ToTokens};
+
+/// A warning about the expansion.
+///
+/// Procedural macros cannot emit warnings on stable Rust, so the warning is
+/// raised through the `deprecated` lint on a constant used at `span`. It can
+/// be silenced with `#[allow(deprecated)]` on the function.
+pub struct Warning {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn into_error(self) -> syn::Error {
+        syn::Error::new(self.span, self.message)
+    }
+}
+
+impl ToTokens for Warning {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let span = Span::mixed_site().located_at(self.span);
+        let message = &self.message;
+
+        tokens.extend(quote_spanned! {span=> {
+            #[deprecated(note = #message)]
+            #[allow(non_upper_case_globals)]
+            const recursive_warning: () = ();
+            let _ = recursive_warning;
+        }});
+    }
+}
@@ -0,0 +1,28 @@
+use recursive::recursive;
+
+#[recursive]
+const fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[recursive]
+const fn drained(n: u64) -> Vec<u64> {
+    if n == 0 {
+        Vec::new()
+    } else {
+        drained(n - 1)
+    }
+}
+
+const GCD: u64 = gcd(832_040, 514_229);
+const DRAINED: Vec<u64> = drained(10_000);
+
+#[test]
+fn const_fn_tail_calls() {
+    assert_eq!(GCD, 1);
+    assert_eq!(DRAINED, Vec::<u64>::new());
+}
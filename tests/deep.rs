@@ -0,0 +1,83 @@
+use recursive::recursive;
+
+#[recursive(stack)]
+fn stack_depth(n: u64) -> u64 {
+    if n == 0 {
+        0
+    } else {
+        1 + stack_depth(n - 1)
+    }
+}
+
+#[recursive(cps)]
+fn cps_depth(n: u64) -> u64 {
+    if n == 0 {
+        0
+    } else {
+        1 + cps_depth(n - 1)
+    }
+}
+
+#[recursive(cps)]
+fn chain(n: u64) -> u64 {
+    let mut length = 1;
+    for rest in (n > 0).then(|| n - 1) {
+        length += chain(rest);
+    }
+    length
+}
+
+#[recursive(cps)]
+fn compositions(n: u64) -> u64 {
+    if n == 0 {
+        return 1;
+    }
+    (1..=n).map(|first| compositions(n - first)).sum()
+}
+
+struct Parity;
+
+#[recursive]
+impl Parity {
+    fn even(n: u64) -> bool {
+        if n == 0 {
+            true
+        } else {
+            Self::odd(n - 1)
+        }
+    }
+
+    fn odd(n: u64) -> bool {
+        if n == 0 {
+            false
+        } else {
+            Self::even(n - 1)
+        }
+    }
+}
+
+#[test]
+fn stack_million_deep() {
+    assert_eq!(stack_depth(1_000_000), 1_000_000);
+}
+
+#[test]
+fn cps_million_deep() {
+    assert_eq!(cps_depth(1_000_000), 1_000_000);
+}
+
+#[test]
+fn cps_for_loop_million_deep() {
+    assert_eq!(chain(1_000_000), 1_000_001);
+}
+
+#[test]
+fn cps_map_sum() {
+    assert_eq!(compositions(16), 1 << 15);
+}
+
+#[test]
+fn impl_calling_each_other_million_deep() {
+    assert!(Parity::even(1_000_000));
+    assert!(Parity::odd(999_999));
+}
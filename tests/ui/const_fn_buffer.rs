@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive]
+const fn gather(n: u64) -> Vec<u64> {
+    if n == 0 {
+        Vec::new()
+    } else {
+        [Vec::new(), gather(n - 1)].concat()
+    }
+}
+
+fn main() {}
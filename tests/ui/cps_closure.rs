@@ -0,0 +1,13 @@
+use recursive::recursive;
+
+struct Tree {
+    value: u64,
+    child: Option<Box<Tree>>,
+}
+
+#[recursive(cps)]
+fn total(tree: &Tree) -> u64 {
+    tree.value + tree.child.as_ref().map(|child| total(child)).unwrap_or(0)
+}
+
+fn main() {}
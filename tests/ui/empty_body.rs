@@ -0,0 +1,6 @@
+use recursive::recursive;
+
+#[recursive]
+const fn nothing(_n: u64) {}
+
+fn main() {}
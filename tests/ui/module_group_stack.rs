@@ -0,0 +1,24 @@
+use recursive::recursive;
+
+#[recursive(stack)]
+mod parity {
+    pub fn is_even(n: u64) -> bool {
+        if n == 0 {
+            true
+        } else {
+            is_odd(n - 1)
+        }
+    }
+
+    pub fn is_odd(n: u64) -> bool {
+        if n == 0 {
+            false
+        } else {
+            is_even(n - 1)
+        }
+    }
+}
+
+fn main() {
+    assert!(parity::is_even(10));
+}
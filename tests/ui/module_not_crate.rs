@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive(module(other::path))]
+fn countdown(n: u64) -> u64 {
+    if n == 0 {
+        0
+    } else {
+        countdown(n - 1)
+    }
+}
+
+fn main() {}
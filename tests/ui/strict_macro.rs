@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive(strict)]
+fn countdown(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    println!("{}", countdown(n - 1));
+    countdown(n - 1)
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive]
+fn count(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let count = tail!(count(n - 1));
+    count + 1
+}
+
+fn main() {}
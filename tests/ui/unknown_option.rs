@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive(stak)]
+fn depth(n: u64) -> u64 {
+    if n == 0 {
+        0
+    } else {
+        1 + depth(n - 1)
+    }
+}
+
+fn main() {}
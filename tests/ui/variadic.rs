@@ -0,0 +1,12 @@
+use recursive::recursive;
+
+#[recursive]
+unsafe extern "C" fn sum(n: u64, _args: ...) -> u64 {
+    if n == 0 {
+        0
+    } else {
+        sum(n - 1)
+    }
+}
+
+fn main() {}